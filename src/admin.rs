@@ -0,0 +1,114 @@
+use crate::{
+    audit, queue,
+    states::{AppState, ConnectionPool},
+};
+use axum::{
+    body::Body,
+    extract::{Query, State},
+    http::{HeaderMap, StatusCode},
+    routing::get,
+    Json, Router,
+};
+use chrono::DateTime;
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+use std::env;
+
+/// Bearer token for the admin routes, they are closed without one.
+static ADMIN_TOKEN: Lazy<Option<String>> = Lazy::new(|| {
+    env::var("ADMIN_TOKEN")
+        .ok()
+        .map(|t| t.trim().to_string())
+        .filter(|t| !t.is_empty())
+});
+
+/// Most audit entries returned by one request.
+static MAX_LIMIT: usize = 1000;
+
+type AdminError = (StatusCode, String);
+
+pub fn get_routes(app_state: AppState) -> Router<AppState, Body> {
+    Router::new()
+        .route("/audit", get(audit_log))
+        .route("/queue", get(merge_queue))
+        .with_state(app_state)
+}
+
+#[derive(Debug, Deserialize)]
+struct AuditQuery {
+    /// Repository full name.
+    repo: Option<String>,
+    user: Option<String>,
+    /// RFC 3339 time.
+    since: Option<String>,
+    /// RFC 3339 time.
+    until: Option<String>,
+    limit: Option<usize>,
+}
+
+async fn audit_log(
+    State(pool): State<ConnectionPool>,
+    headers: HeaderMap,
+    Query(query): Query<AuditQuery>,
+) -> Result<Json<Vec<audit::Entry>>, AdminError> {
+    authorize(&headers)?;
+
+    let since = parse_time(query.since.as_deref())?;
+    let until = parse_time(query.until.as_deref())?;
+    let filter = audit::Filter {
+        repo: query.repo.as_deref(),
+        user: query.user.as_deref(),
+        since,
+        until,
+        limit: query.limit.unwrap_or(100).min(MAX_LIMIT),
+    };
+    let entries = audit::query(&pool, &filter).await;
+
+    Ok(Json(entries))
+}
+
+#[derive(Debug, Deserialize)]
+struct QueueQuery {
+    /// Repository full name.
+    repo: String,
+}
+
+async fn merge_queue(
+    State(pool): State<ConnectionPool>,
+    headers: HeaderMap,
+    Query(query): Query<QueueQuery>,
+) -> Result<Json<Vec<queue::Queued>>, AdminError> {
+    authorize(&headers)?;
+
+    Ok(Json(queue::list(&pool, &query.repo).await))
+}
+
+fn authorize(headers: &HeaderMap) -> Result<(), AdminError> {
+    let unauthorized = || (StatusCode::UNAUTHORIZED, "Unauthorized".to_string());
+
+    let expected = ADMIN_TOKEN.as_ref().ok_or_else(unauthorized)?;
+    let given = headers
+        .get("authorization")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .ok_or_else(unauthorized)?;
+
+    // Compare every byte so the time taken does not leak how much matched
+    let matches = given.len() == expected.len()
+        && given
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0;
+    matches.then_some(()).ok_or_else(unauthorized)
+}
+
+/// RFC 3339 time to unix milliseconds.
+fn parse_time(time: Option<&str>) -> Result<Option<i64>, AdminError> {
+    time.map(|t| {
+        DateTime::parse_from_rfc3339(t)
+            .map(|t| t.timestamp_millis())
+            .map_err(|_| (StatusCode::BAD_REQUEST, format!("Bad time: {t}")))
+    })
+    .transpose()
+}
@@ -0,0 +1,139 @@
+use crate::states::AppState;
+use reqwest::{Response, StatusCode, Url};
+use serde_json::{json, Value};
+
+/// GET a GitHub api url and return the json body if the request succeeded.
+/// Failed requests are only logged at debug, since a 404 is often an answer.
+pub async fn get_json(app: &AppState, url: &str) -> Option<Value> {
+    let request = {
+        let mut token = app.access_token.write().await;
+        token.get(&app.data, url).await.await
+    };
+    match request {
+        Ok(res) if res.status().is_success() => res.json().await.ok(),
+        Ok(res) => {
+            tracing::debug!("GitHub api '{url}': status code {}", res.status());
+            None
+        }
+        Err(err) => {
+            tracing::error!("GitHub api error '{url}': {err}");
+            None
+        }
+    }
+}
+
+/// GET a GitHub api url, telling a missing resource apart from a failed request.
+/// Ok(None) on a 404, Err on any other failure.
+pub async fn get_optional(app: &AppState, url: &str) -> Result<Option<Value>, ()> {
+    let request = {
+        let mut token = app.access_token.write().await;
+        token.get(&app.data, url).await.await
+    };
+    match request {
+        Ok(res) if res.status().is_success() => res.json().await.map(Some).map_err(|_| ()),
+        Ok(res) if res.status() == StatusCode::NOT_FOUND => Ok(None),
+        Ok(res) => {
+            tracing::error!("GitHub api error '{url}': status code {}", res.status());
+            Err(())
+        }
+        Err(err) => {
+            tracing::error!("GitHub api error '{url}': {err}");
+            Err(())
+        }
+    }
+}
+
+/// GET every page of a GitHub api list, 100 items at a time.
+pub async fn get_all(app: &AppState, url: &str) -> Option<Vec<Value>> {
+    let sep = if url.contains('?') { '&' } else { '?' };
+    let mut items = Vec::new();
+    for page in 1.. {
+        let json = get_json(app, &format!("{url}{sep}per_page=100&page={page}")).await?;
+        let list = json.as_array()?;
+        items.extend(list.iter().cloned());
+        if list.len() < 100 {
+            break;
+        }
+    }
+    Some(items)
+}
+
+/// POST json to a GitHub api url.
+/// Returns the json body if the request succeeded, or null if there was none.
+pub async fn post_json(app: &AppState, url: &str, payload: &Value) -> Option<Value> {
+    let request = {
+        let mut token = app.access_token.write().await;
+        token.post_json(&app.data, url, payload).await.await
+    };
+    read_json(url, request).await
+}
+
+/// PATCH json to a GitHub api url.
+/// Returns the json body if the request succeeded, or null if there was none.
+pub async fn patch_json(app: &AppState, url: &str, payload: &Value) -> Option<Value> {
+    let request = {
+        let mut token = app.access_token.write().await;
+        token.patch_json(&app.data, url, payload).await.await
+    };
+    read_json(url, request).await
+}
+
+/// PUT json to a GitHub api url.
+/// Returns the json body if the request succeeded, or null if there was none.
+pub async fn put_json(app: &AppState, url: &str, payload: &Value) -> Option<Value> {
+    let request = {
+        let mut token = app.access_token.write().await;
+        token.put_json(&app.data, url, payload).await.await
+    };
+    read_json(url, request).await
+}
+
+/// DELETE a GitHub api url.
+/// Returns the json body if the request succeeded, or null if there was none.
+pub async fn delete(app: &AppState, url: &str) -> Option<Value> {
+    let request = {
+        let mut token = app.access_token.write().await;
+        token.delete(&app.data, url).await.await
+    };
+    read_json(url, request).await
+}
+
+/// Add a reaction, like `rocket`, using the reactions url of a comment.
+pub async fn react(app: &AppState, reactions_url: &str, content: &str) {
+    let json = json!({ "content": content });
+    if post_json(app, reactions_url, &json).await.is_some() {
+        tracing::debug!("Added reaction {content} to '{reactions_url}'");
+    }
+}
+
+/// Post a comment using the comments url of an issue or pull request.
+pub async fn comment(app: &AppState, comments_url: &str, body: &str) -> Option<Value> {
+    let json = json!({ "body": body });
+    post_json(app, comments_url, &json).await
+}
+
+/// Append a path segment to a url, escaping it, for names like labels that can hold anything.
+pub fn join_segment(url: &str, segment: &str) -> String {
+    let mut url = Url::parse(url).unwrap();
+    url.path_segments_mut().unwrap().push(segment);
+    url.to_string()
+}
+
+async fn read_json(url: &str, request: Result<Response, reqwest::Error>) -> Option<Value> {
+    match request {
+        Ok(res) if res.status().is_success() => {
+            let body = res.bytes().await.ok()?;
+            Some(serde_json::from_slice(&body).unwrap_or(Value::Null))
+        }
+        Ok(res) => {
+            let status = res.status();
+            let body = res.text().await.unwrap_or_default();
+            tracing::error!("GitHub api error '{url}': status code {status}\n{body}");
+            None
+        }
+        Err(err) => {
+            tracing::error!("GitHub api error '{url}': {err}");
+            None
+        }
+    }
+}
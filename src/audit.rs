@@ -0,0 +1,170 @@
+use crate::{sources::Source, states::ConnectionPool};
+use bb8_redis::redis::cmd;
+use chrono::{Duration, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// Sorted set of entries, scored by time. Each repository and user has its own set too.
+pub static AUDIT_KEY: &str = "audit";
+static AUDIT_KEEP_DAYS: i64 = 90;
+/// Entries read at a time when a query filters them.
+static QUERY_BATCH: usize = 500;
+
+fn repo_key(repo: &str) -> String {
+    format!("{AUDIT_KEY}.repo.{}", repo.to_lowercase())
+}
+
+fn user_key(user: &str) -> String {
+    format!("{AUDIT_KEY}.user.{}", user.to_lowercase())
+}
+
+/// A parsed command, who sent it and what the bot did about it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Entry {
+    /// Unix time in milliseconds.
+    pub at: i64,
+    pub user: String,
+    pub association: String,
+    /// Repository full name.
+    pub repo: String,
+    /// Pull request or issue number.
+    pub number: u64,
+    pub command: String,
+    pub workflow: Option<String>,
+    pub inputs: Value,
+    pub decision: Decision,
+    /// Why the command was denied.
+    pub reason: Option<String>,
+    /// What came of the api calls for an allowed command.
+    pub outcome: Option<String>,
+}
+impl Entry {
+    /// Start an entry for a command from a comment or review.
+    pub fn new(source: &Source, command: &str) -> Self {
+        Self {
+            at: Utc::now().timestamp_millis(),
+            user: source.user.clone(),
+            association: source.association.clone(),
+            repo: source.repo_name().to_string(),
+            number: source.number,
+            command: command.to_string(),
+            workflow: None,
+            inputs: Value::Null,
+            decision: Decision::Denied,
+            reason: None,
+            outcome: None,
+        }
+    }
+
+    pub fn deny(&mut self, reason: impl Into<String>) {
+        self.decision = Decision::Denied;
+        self.reason = Some(reason.into());
+    }
+
+    pub fn allow(&mut self, outcome: impl Into<String>) {
+        self.decision = Decision::Allowed;
+        self.outcome = Some(outcome.into());
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Decision {
+    Allowed,
+    Denied,
+}
+
+/// Store an entry, dropping entries older than the retention period.
+pub async fn record(pool: &ConnectionPool, entry: &Entry) {
+    let json = serde_json::to_string(entry).unwrap();
+    tracing::info!("Audit: {json}");
+
+    let cutoff = (Utc::now() - Duration::days(AUDIT_KEEP_DAYS)).timestamp_millis();
+    let mut conn = pool.as_ref().unwrap().get().await.unwrap();
+    for key in [
+        AUDIT_KEY.to_string(),
+        repo_key(&entry.repo),
+        user_key(&entry.user),
+    ] {
+        let _: usize = cmd("ZADD")
+            .arg(&key)
+            .arg(entry.at)
+            .arg(&json)
+            .query_async(&mut *conn)
+            .await
+            .unwrap();
+        let _: usize = cmd("ZREMRANGEBYSCORE")
+            .arg(&key)
+            .arg("-inf")
+            .arg(format!("({cutoff}"))
+            .query_async(&mut *conn)
+            .await
+            .unwrap();
+        // Sets of repositories and users that go quiet are dropped whole
+        if !key.eq(AUDIT_KEY) {
+            let _: usize = cmd("EXPIRE")
+                .arg(&key)
+                .arg(Duration::days(AUDIT_KEEP_DAYS).num_seconds())
+                .query_async(&mut *conn)
+                .await
+                .unwrap();
+        }
+    }
+}
+
+/// Which entries to read, times are unix milliseconds.
+#[derive(Debug, Clone)]
+pub struct Filter<'a> {
+    /// Repository full name.
+    pub repo: Option<&'a str>,
+    pub user: Option<&'a str>,
+    pub since: Option<i64>,
+    pub until: Option<i64>,
+    pub limit: usize,
+}
+impl Filter<'_> {
+    fn matches(&self, entry: &Entry) -> bool {
+        self.repo.is_none_or(|r| entry.repo.eq_ignore_ascii_case(r))
+            && self.user.is_none_or(|u| entry.user.eq_ignore_ascii_case(u))
+    }
+}
+
+/// Entries matching a filter, newest first.
+/// Reads the repository's or user's own set, a page at a time, until the limit is reached.
+pub async fn query(pool: &ConnectionPool, filter: &Filter<'_>) -> Vec<Entry> {
+    let key = match (filter.repo, filter.user) {
+        (Some(repo), _) => repo_key(repo),
+        (None, Some(user)) => user_key(user),
+        (None, None) => AUDIT_KEY.to_string(),
+    };
+    let max = filter.until.map_or("+inf".to_string(), |t| t.to_string());
+    let min = filter.since.map_or("-inf".to_string(), |t| t.to_string());
+
+    let mut conn = pool.as_ref().unwrap().get().await.unwrap();
+    let mut found = Vec::new();
+    let mut offset = 0;
+    while found.len() < filter.limit {
+        let batch: Vec<String> = cmd("ZREVRANGEBYSCORE")
+            .arg(&key)
+            .arg(&max)
+            .arg(&min)
+            .arg("LIMIT")
+            .arg(offset)
+            .arg(QUERY_BATCH)
+            .query_async(&mut *conn)
+            .await
+            .unwrap();
+        offset += batch.len();
+        found.extend(
+            batch
+                .iter()
+                .filter_map(|e| serde_json::from_str(e).ok())
+                .filter(|e| filter.matches(e)),
+        );
+        if batch.len() < QUERY_BATCH {
+            break;
+        }
+    }
+    found.truncate(filter.limit);
+    found
+}
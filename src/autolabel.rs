@@ -0,0 +1,103 @@
+use crate::{
+    api, config,
+    states::{AppState, ConnectionPool},
+};
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet};
+
+/// Label a pull request by the files it changes when it is opened or pushed to.
+pub async fn changed_paths(app: &AppState, pool: &ConnectionPool, payload: &Value) {
+    if !matches!(
+        payload["action"].as_str().unwrap(),
+        "opened" | "synchronize"
+    ) {
+        return;
+    }
+
+    let config = match config::load(app, pool, &payload["repository"]).await {
+        None => return,
+        Some(config) => config,
+    };
+    let rules = &config.auto_label.rules;
+    if !config.features.auto_label || rules.is_empty() {
+        return;
+    }
+
+    let pull = &payload["pull_request"];
+    let pull_url = pull["url"].as_str().unwrap();
+    let files = match api::get_all(app, &format!("{pull_url}/files")).await {
+        None => return,
+        Some(files) => files,
+    };
+    // A rename touches both places
+    let paths: Vec<&str> = files
+        .iter()
+        .flat_map(|f| [&f["filename"], &f["previous_filename"]])
+        .filter_map(|p| p.as_str())
+        .collect();
+
+    let wanted: HashSet<&String> = globs(rules)
+        .iter()
+        .filter(|(_, set)| paths.iter().any(|p| set.is_match(p)))
+        .map(|(label, _)| *label)
+        .collect();
+    let current: HashSet<&str> = pull["labels"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .filter_map(|l| l["name"].as_str())
+        .collect();
+
+    let issue_url = pull["issue_url"].as_str().unwrap();
+    let number = pull["number"].as_u64().unwrap();
+    let add: Vec<&String> = wanted
+        .iter()
+        .filter(|l| !current.contains(l.as_str()))
+        .copied()
+        .collect();
+    if !add.is_empty() {
+        let json = json!({ "labels": add });
+        if api::post_json(app, &format!("{issue_url}/labels"), &json)
+            .await
+            .is_some()
+        {
+            tracing::info!("Labeled pull request {number} with {add:?}");
+        }
+    }
+
+    // Only labels from the rules are taken off, others were added by people
+    if config.auto_label.remove_stale {
+        for label in rules.keys() {
+            if current.contains(label.as_str()) && !wanted.contains(label) {
+                let url = api::join_segment(&format!("{issue_url}/labels"), label);
+                if api::delete(app, &url).await.is_some() {
+                    tracing::info!("Removed stale label {label} from pull request {number}");
+                }
+            }
+        }
+    }
+}
+
+/// Build a glob set per label.
+fn globs(rules: &HashMap<String, Vec<String>>) -> Vec<(&String, GlobSet)> {
+    rules
+        .iter()
+        .map(|(label, patterns)| (label, glob_set(patterns)))
+        .collect()
+}
+
+/// Build a glob set, skipping patterns that do not parse.
+/// `*` stays within a directory, `**` crosses them.
+pub fn glob_set(patterns: &[String]) -> GlobSet {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        match GlobBuilder::new(pattern).literal_separator(true).build() {
+            Ok(glob) => {
+                builder.add(glob);
+            }
+            Err(err) => tracing::error!("Bad glob {pattern}: {err}"),
+        }
+    }
+    builder.build().unwrap_or_else(|_| GlobSet::empty())
+}
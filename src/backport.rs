@@ -0,0 +1,272 @@
+use crate::{api, audit, commands::Command, config::RepoConfig, sources::Source, states::AppState};
+use serde_json::{json, Value};
+use std::collections::HashSet;
+
+/// `backport <branch>...`: cherry-pick a merged pull request onto each branch and open pull requests for them.
+pub async fn command(
+    app: &AppState,
+    config: &RepoConfig,
+    source: &Source,
+    command: &Command<'_>,
+    pull: &Value,
+    entry: &mut audit::Entry,
+) {
+    let login = source.user.as_str();
+    let targets: Vec<&str> = command
+        .args
+        .unwrap_or_default()
+        .split_whitespace()
+        .collect();
+    entry.inputs = json!({ "targets": targets });
+    if targets.is_empty() {
+        let body = format!("Usage: `{} backport <branch>...`", config.prefix);
+        api::comment(app, &source.reply_url, &body).await;
+        entry.deny("no target branch");
+        return;
+    }
+
+    let pull_url = source.pull_url.as_deref().unwrap();
+    if !pull["merged"].as_bool().unwrap_or(false) {
+        let body = format!("@{login} only merged pull requests can be backported.");
+        api::comment(app, &source.reply_url, &body).await;
+        entry.deny("not merged");
+        return;
+    }
+
+    let repo_url = source.repo["url"].as_str().unwrap();
+    let mut commits = match merged_commits(app, repo_url, pull_url, pull).await {
+        None => {
+            entry.allow("could not get merged commits");
+            return;
+        }
+        Some(commits) => commits,
+    };
+    for commit in &mut commits {
+        let message = format!(
+            "{}\n\n(cherry picked from commit {})",
+            commit["message"].as_str().unwrap_or_default(),
+            commit["sha"].as_str().unwrap()
+        );
+        commit["message"] = Value::String(message);
+    }
+
+    let mut lines = Vec::new();
+    let mut opened = 0;
+    for target in &targets {
+        match backport(app, repo_url, pull, &commits, target).await {
+            Ok(backport) => {
+                opened += 1;
+                let url = backport["html_url"].as_str().unwrap_or_default();
+                lines.push(format!("- `{target}`: {url}"));
+            }
+            Err(reason) => lines.push(format!("- `{target}`: {reason}")),
+        }
+    }
+
+    let body = format!(
+        "@{login} backports of #{}:\n{}",
+        source.number,
+        lines.join("\n")
+    );
+    api::comment(app, &source.reply_url, &body).await;
+    entry.allow(format!("opened {opened} of {} backports", targets.len()));
+}
+
+/// Commits the pull request put on its base branch, oldest first.
+/// A rebase merge puts a copy of every commit there, other merges a single commit.
+async fn merged_commits(
+    app: &AppState,
+    repo_url: &str,
+    pull_url: &str,
+    pull: &Value,
+) -> Option<Vec<Value>> {
+    let merge_sha = pull["merge_commit_sha"].as_str()?;
+    let merged = api::get_json(app, &format!("{repo_url}/git/commits/{merge_sha}")).await?;
+    let count = pull["commits"].as_u64().unwrap_or(1) as usize;
+    if count <= 1 || merged["parents"].as_array()?.len() > 1 {
+        return Some(vec![merged]);
+    }
+
+    // Rebased commits keep their messages, a squash does not match them
+    let originals = api::get_all(app, &format!("{pull_url}/commits")).await?;
+    let mut commits = vec![merged];
+    while commits.len() < count {
+        let parent = commits.last().unwrap()["parents"][0]["sha"].as_str()?;
+        commits.push(api::get_json(app, &format!("{repo_url}/git/commits/{parent}")).await?);
+    }
+    commits.reverse();
+
+    let rebased = originals.len() == commits.len()
+        && originals
+            .iter()
+            .zip(&commits)
+            .all(|(o, c)| o["commit"]["message"].eq(&c["message"]));
+    if rebased {
+        Some(commits)
+    }
+    else {
+        Some(vec![commits.pop().unwrap()])
+    }
+}
+
+/// Cherry-pick commits onto a new branch from `target` and open a pull request for it.
+/// Fails with what to tell the user.
+async fn backport(
+    app: &AppState,
+    repo_url: &str,
+    pull: &Value,
+    commits: &[Value],
+    target: &str,
+) -> Result<Value, String> {
+    let number = pull["number"].as_u64().unwrap();
+    let branch_url = api::join_segment(&format!("{repo_url}/branches"), target);
+    let target_head = match api::get_json(app, &branch_url).await {
+        None => return Err("not a branch".to_string()),
+        Some(branch) => branch["commit"]["sha"].as_str().unwrap().to_string(),
+    };
+
+    let branch = format!("backport-{number}-to-{target}");
+    let json = json!({ "ref": format!("refs/heads/{branch}"), "sha": target_head });
+    if api::post_json(app, &format!("{repo_url}/git/refs"), &json)
+        .await
+        .is_none()
+    {
+        return Err(format!("could not create `{branch}`, it may already exist"));
+    }
+
+    let picked = cherry_pick(app, repo_url, &branch, &target_head, commits).await;
+    let head = match picked {
+        Ok(head) => head,
+        Err(reason) => {
+            api::delete(app, &format!("{repo_url}/git/refs/heads/{branch}")).await;
+            return Err(reason);
+        }
+    };
+    tracing::info!("Backported #{number} to {target} as {head}");
+
+    let title = pull["title"].as_str().unwrap_or_default();
+    let json = json!({
+        "title": format!("[{target}] {title}"),
+        "head": branch,
+        "base": target,
+        "body": format!("Backport of #{number} to `{target}`."),
+    });
+    api::post_json(app, &format!("{repo_url}/pulls"), &json)
+        .await
+        .ok_or_else(|| format!("pushed `{branch}` but could not open a pull request"))
+}
+
+/// Cherry-pick commits onto a branch at `head`, returning the new head.
+/// Commits are git commit objects, their messages are kept as they are.
+///
+/// GitHub can merge but not cherry-pick, so each commit is merged into a commit with the branch's tree and the
+/// commit's parent. The merge base is then the parent, so the merge applies only the commit's changes.
+pub async fn cherry_pick(
+    app: &AppState,
+    repo_url: &str,
+    branch: &str,
+    head: &str,
+    commits: &[Value],
+) -> Result<String, String> {
+    let api_error = || "GitHub api error".to_string();
+    let ref_url = format!("{repo_url}/git/refs/heads/{branch}");
+
+    let mut head = head.to_string();
+    let mut tree = api::get_json(app, &format!("{repo_url}/git/commits/{head}"))
+        .await
+        .ok_or_else(api_error)?["tree"]["sha"]
+        .as_str()
+        .unwrap()
+        .to_string();
+
+    for commit in commits {
+        let sha = commit["sha"].as_str().unwrap();
+        let parent = commit["parents"][0]["sha"].as_str().unwrap();
+
+        let json = json!({ "message": "Cherry-pick base", "tree": tree, "parents": [parent] });
+        let sibling = create_commit(app, repo_url, &json)
+            .await
+            .ok_or_else(api_error)?;
+        let json = json!({ "sha": sibling, "force": true });
+        api::patch_json(app, &ref_url, &json)
+            .await
+            .ok_or_else(api_error)?;
+
+        let json = json!({ "base": branch, "head": sha, "commit_message": "Cherry-pick merge" });
+        match api::post_json(app, &format!("{repo_url}/merges"), &json).await {
+            None => {
+                let files = conflicts(app, repo_url, parent, sha, &head).await;
+                return Err(format!(
+                    "`{}` does not apply cleanly, these files changed on both sides:\n{}",
+                    &sha[..7],
+                    files
+                        .iter()
+                        .map(|f| format!("  - `{f}`"))
+                        .collect::<Vec<_>>()
+                        .join("\n")
+                ));
+            }
+            // Nothing to merge, the changes are already there
+            Some(Value::Null) => continue,
+            Some(merged) => {
+                tree = merged["commit"]["tree"]["sha"]
+                    .as_str()
+                    .unwrap()
+                    .to_string()
+            }
+        }
+
+        let json = json!({
+            "message": commit["message"],
+            "tree": tree,
+            "parents": [head],
+            "author": commit["author"],
+        });
+        head = create_commit(app, repo_url, &json)
+            .await
+            .ok_or_else(api_error)?;
+    }
+
+    let json = json!({ "sha": head, "force": true });
+    api::patch_json(app, &ref_url, &json)
+        .await
+        .ok_or_else(api_error)?;
+    Ok(head)
+}
+
+async fn create_commit(app: &AppState, repo_url: &str, json: &Value) -> Option<String> {
+    let commit = api::post_json(app, &format!("{repo_url}/git/commits"), json).await?;
+    commit["sha"].as_str().map(str::to_string)
+}
+
+/// Files a commit changed that also changed on the target since it split off.
+async fn conflicts(
+    app: &AppState,
+    repo_url: &str,
+    parent: &str,
+    sha: &str,
+    head: &str,
+) -> Vec<String> {
+    let changed = |base: String, head: String| async move {
+        let url = format!("{repo_url}/compare/{base}...{head}");
+        api::get_json(app, &url)
+            .await
+            .and_then(|c| c["files"].as_array().cloned())
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|f| f["filename"].as_str().map(str::to_string))
+            .collect::<Vec<_>>()
+    };
+
+    let ours: HashSet<String> = changed(parent.to_string(), head.to_string())
+        .await
+        .into_iter()
+        .collect();
+    let mut files: Vec<String> = changed(parent.to_string(), sha.to_string())
+        .await
+        .into_iter()
+        .filter(|f| ours.contains(f))
+        .collect();
+    files.sort();
+    files
+}
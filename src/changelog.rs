@@ -0,0 +1,95 @@
+use crate::{
+    api, autolabel, checks, config,
+    states::{AppState, ConnectionPool},
+};
+use serde_json::{json, Value};
+
+static CHECK_NAME: &str = "changelog";
+
+/// Fail a pull request that does not touch the changelog, unless it has the skip label.
+pub async fn pull_request(app: &AppState, pool: &ConnectionPool, payload: &Value) {
+    let action = payload["action"].as_str().unwrap();
+    if !matches!(
+        action,
+        "opened" | "synchronize" | "reopened" | "labeled" | "unlabeled"
+    ) {
+        return;
+    }
+
+    let repo = &payload["repository"];
+    let config = match config::load(app, pool, repo).await {
+        None => return,
+        Some(config) => config,
+    };
+    let settings = &config.changelog;
+    if !config.features.changelog {
+        return;
+    }
+    if action.ends_with("labeled")
+        && !payload["label"]["name"]
+            .as_str()
+            .eq(&Some(&settings.skip_label))
+    {
+        return;
+    }
+
+    let pull = &payload["pull_request"];
+    let skipped = checks::has_label(pull, &settings.skip_label);
+    let touched = if skipped {
+        false
+    }
+    else {
+        let pull_url = pull["url"].as_str().unwrap();
+        let files = match api::get_all(app, &format!("{pull_url}/files")).await {
+            None => return,
+            Some(files) => files,
+        };
+        let changelog = autolabel::glob_set(&settings.paths);
+        files
+            .iter()
+            .filter_map(|f| f["filename"].as_str())
+            .any(|f| changelog.is_match(f))
+    };
+
+    let guide = format!(
+        "{}/blob/{}/{}",
+        repo["html_url"].as_str().unwrap(),
+        repo["default_branch"].as_str().unwrap(),
+        settings.contributing
+    );
+    let output = match (touched, skipped) {
+        (true, _) => {
+            json!({ "title": "Changelog updated", "summary": "The changelog has an entry." })
+        }
+        (_, true) => json!({
+            "title": "Changelog skipped",
+            "summary": format!("The `{}` label is set, no entry needed.", settings.skip_label),
+        }),
+        _ => json!({
+            "title": "No changelog entry",
+            "summary": format!(
+                "Add an entry to {}, or a maintainer can add the `{}` label for changes users will not notice.\n\n\
+                See the [contributing guide]({guide}) for how entries are written.",
+                settings
+                    .paths
+                    .iter()
+                    .map(|p| format!("`{p}`"))
+                    .collect::<Vec<_>>()
+                    .join(" or "),
+                settings.skip_label
+            ),
+        }),
+    };
+
+    let repo_url = repo["url"].as_str().unwrap();
+    let head_sha = pull["head"]["sha"].as_str().unwrap();
+    checks::complete(
+        app,
+        repo_url,
+        head_sha,
+        CHECK_NAME,
+        touched || skipped,
+        output,
+    )
+    .await;
+}
@@ -0,0 +1,36 @@
+use crate::{api, states::AppState};
+use serde_json::{json, Value};
+
+/// Post a finished check run on a commit, `output` being the check run's title, summary and annotations.
+pub async fn complete(
+    app: &AppState,
+    repo_url: &str,
+    head_sha: &str,
+    name: &str,
+    success: bool,
+    output: Value,
+) {
+    let conclusion = if success { "success" } else { "failure" };
+    let json = json!({
+        "name": name,
+        "head_sha": head_sha,
+        "status": "completed",
+        "conclusion": conclusion,
+        "output": output,
+    });
+    if api::post_json(app, &format!("{repo_url}/check-runs"), &json)
+        .await
+        .is_some()
+    {
+        tracing::info!("Check {name} on {head_sha}: {conclusion}");
+    }
+}
+
+/// Whether a pull request payload has a label.
+pub fn has_label(pull: &Value, label: &str) -> bool {
+    pull["labels"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .any(|l| l["name"].as_str().eq(&Some(label)))
+}
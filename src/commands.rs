@@ -0,0 +1,473 @@
+use crate::{
+    api, audit, backport,
+    config::{self, RepoConfig},
+    dco, deploy, dispatch, issues, locks, merge, policy, queue, ratelimit,
+    runs::{self, Tracked},
+    sources::Source,
+    states::{AppState, ConnectionPool},
+    triage, update,
+};
+use serde_json::Value;
+
+/// Commands run by the bot itself, they take their names over workflows.
+static BUILT_IN: [&str; 19] = [
+    "backport",
+    "dco-override",
+    "deploy",
+    "lock",
+    "unlock",
+    "locks",
+    "close",
+    "reopen",
+    "duplicate-of",
+    "transfer",
+    "merge",
+    "automerge",
+    "queue",
+    "label",
+    "unlabel",
+    "assign",
+    "request-review",
+    "update",
+    "rebase",
+];
+
+/// A command found in a comment, like `!harmful test2 --force {"testing-in": "hi"}` or `/test2`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Command<'a> {
+    pub name: &'a str,
+    /// Flags like `--force` given right after the command name, without the dashes.
+    pub flags: Vec<&'a str>,
+    /// Everything after the command name and flags.
+    pub args: Option<&'a str>,
+}
+impl Command<'_> {
+    pub fn flag(&self, flag: &str) -> bool {
+        self.flags.iter().any(|f| f.eq(&flag))
+    }
+}
+
+/// Parse a comment body for a command using the repository's prefix.
+/// With `slash` enabled, `/name` works without the prefix.
+pub fn parse<'a>(config: &RepoConfig, body: &'a str) -> Option<Command<'a>> {
+    let body = body.trim();
+    let contents = match body.strip_prefix(config.prefix.as_str()) {
+        Some(c) if c.starts_with(char::is_whitespace) => c.trim(),
+        _ if config.slash => body.strip_prefix('/')?,
+        _ => return None,
+    };
+
+    let (name, mut rest) = match contents.split_once(char::is_whitespace) {
+        None => (contents, ""),
+        Some((name, rest)) => (name, rest.trim()),
+    };
+    if name.is_empty() {
+        return None;
+    }
+
+    let mut flags = Vec::new();
+    while let Some(flagged) = rest.strip_prefix("--") {
+        let (flag, more) = flagged
+            .split_once(char::is_whitespace)
+            .unwrap_or((flagged, ""));
+        flags.push(flag);
+        rest = more.trim();
+    }
+    let args = (!rest.is_empty()).then_some(rest);
+
+    Some(Command { name, flags, args })
+}
+
+/// Run a command from a comment or review on an open issue or pull request.
+/// `backport`, `reopen` and `lock` also work once it is closed, pull request commands are ignored on issues.
+/// Edited sources only run a command if the edit added it.
+pub async fn comment_event(app: &AppState, pool: &ConnectionPool, event: &str, payload: &Value) {
+    let source = match Source::from_event(event, payload) {
+        None => return,
+        Some(source) => source,
+    };
+    tracing::debug!("Found {:?} on #{}.", source.kind, source.number);
+
+    let config = match config::load(app, pool, &source.repo).await {
+        None => return,
+        Some(config) => config,
+    };
+    if !config.features.commands {
+        return;
+    }
+
+    // Parse comment for workflow name and possible args
+    let command = match parse(&config, &source.body) {
+        None => return,
+        Some(c) => c,
+    };
+    if let Some(previous) = &source.previous_body {
+        if parse(&config, previous).is_some_and(|p| p.eq(&command)) {
+            return;
+        }
+    }
+    let mut entry = audit::Entry::new(&source, command.name);
+    run(app, pool, &config, &source, &command, &mut entry).await;
+    audit::record(pool, &entry).await;
+}
+
+/// Check a parsed command and run it, noting what happened in the audit entry.
+async fn run(
+    app: &AppState,
+    pool: &ConnectionPool,
+    config: &RepoConfig,
+    source: &Source,
+    command: &Command<'_>,
+    entry: &mut audit::Entry,
+) {
+    let pull_only = matches!(
+        command.name,
+        "backport"
+            | "dco-override"
+            | "deploy"
+            | "merge"
+            | "automerge"
+            | "queue"
+            | "update"
+            | "rebase"
+    );
+    let when_closed = matches!(command.name, "backport" | "reopen" | "lock");
+    if source.pull_url.is_none() && pull_only {
+        entry.deny("only works on pull requests");
+        return;
+    }
+    if !source.open && !when_closed {
+        entry.deny("closed");
+        return;
+    }
+
+    // Built in commands take their names over workflows
+    if !BUILT_IN.contains(&command.name) {
+        run_workflow(app, pool, config, source, command, entry).await;
+        return;
+    }
+    if !config.command_enabled(command.name) {
+        entry.deny("command is not enabled");
+        return;
+    }
+
+    // Deploy environments have their own rules, and authors may always triage their own issues.
+    // Listing locks and usage replies for unknown environments go through the command's own entry.
+    let environment = matches!(command.name, "deploy" | "lock" | "unlock")
+        && locks::names_environment(config, command);
+    let own_triage = matches!(
+        command.name,
+        "label" | "unlabel" | "assign" | "request-review"
+    ) && source.author.eq_ignore_ascii_case(&source.user);
+    let rules = match command.name {
+        "automerge" | "queue" => merge::MERGE_POLICY,
+        name => name,
+    };
+    if !environment
+        && !own_triage
+        && !policy::command_allowed(app, &config.permissions, source, rules).await
+    {
+        entry.deny("not allowed by policy");
+        return;
+    }
+
+    // Merges get the pull request themselves, to wait for GitHub to check if it can be merged
+    let pull = match source.pull_url.as_deref() {
+        Some(pull_url) if pull_only && !rules.eq(merge::MERGE_POLICY) => {
+            match api::get_json(app, pull_url).await {
+                None => {
+                    tracing::error!("Pull request api error '{pull_url}'");
+                    entry.allow("could not get pull request");
+                    return;
+                }
+                Some(pull) => pull,
+            }
+        }
+        _ => Value::Null,
+    };
+
+    match command.name {
+        "backport" => backport::command(app, config, source, command, &pull, entry).await,
+        "dco-override" => dco::command(app, pool, source, &pull, entry).await,
+        "deploy" => deploy::command(app, pool, config, source, command, &pull, entry).await,
+        "lock" if !locks::names_environment(config, command) => {
+            issues::command(app, config, source, command, entry).await
+        }
+        "lock" | "unlock" | "locks" => {
+            locks::command(app, pool, config, source, command, entry).await
+        }
+        "close" | "reopen" | "duplicate-of" | "transfer" => {
+            issues::command(app, config, source, command, entry).await
+        }
+        "merge" | "automerge" => merge::command(app, pool, config, source, command, entry).await,
+        "queue" => queue::command(app, pool, config, source, command, entry).await,
+        "label" | "unlabel" | "assign" | "request-review" => {
+            triage::command(app, config, source, command, entry).await
+        }
+        _ => update::command(app, source, command, &pull, entry).await,
+    }
+}
+
+/// Dispatch the workflow mapped to a label added to an open pull request.
+pub async fn labeled(app: &AppState, pool: &ConnectionPool, payload: &Value) {
+    if !payload["action"].as_str().unwrap().eq("labeled")
+        || !payload["pull_request"]["state"]
+            .as_str()
+            .unwrap()
+            .eq("open")
+    {
+        return;
+    }
+
+    let config = match config::load(app, pool, &payload["repository"]).await {
+        None => return,
+        Some(config) => config,
+    };
+    let label = payload["label"]["name"].as_str().unwrap();
+    let name = match config.label_triggers.workflows.get(label) {
+        Some(name) if config.features.label_triggers => name.as_str(),
+        _ => return,
+    };
+    tracing::debug!("Found trigger label {label} on pull request.");
+
+    let repo = &payload["repository"];
+    let sender = payload["sender"]["login"].as_str().unwrap();
+    let permission = policy::repo_permission(app, repo["url"].as_str().unwrap(), sender).await;
+    let association = policy::association(app, repo, sender, permission).await;
+
+    let source = Source::from_label(payload, association, permission);
+    let command = Command {
+        name,
+        flags: Vec::new(),
+        args: None,
+    };
+    let mut entry = audit::Entry::new(&source, command.name);
+    run_workflow(app, pool, &config, &source, &command, &mut entry).await;
+    audit::record(pool, &entry).await;
+
+    if config.label_triggers.remove {
+        let issue_url = payload["pull_request"]["issue_url"].as_str().unwrap();
+        let url = api::join_segment(&format!("{issue_url}/labels"), label);
+        if api::delete(app, &url).await.is_some() {
+            tracing::debug!(
+                "Removed trigger label {label} from pull request {}",
+                source.number
+            );
+        }
+    }
+}
+
+/// Dispatch the workflow for a command on a pull request or issue, noting what happened in the audit entry.
+async fn run_workflow(
+    app: &AppState,
+    pool: &ConnectionPool,
+    config: &RepoConfig,
+    source: &Source,
+    command: &Command<'_>,
+    entry: &mut audit::Entry,
+) {
+    let inputs = match command.args {
+        None => None,
+        Some(args) => match serde_json::from_str::<Value>(args) {
+            Ok(v) if v.is_object() => Some(v),
+            _ => {
+                tracing::debug!("Args for {} are not a json object: {args}", command.name);
+                entry.inputs = Value::String(args.to_string());
+                entry.deny("args are not a json object");
+                return;
+            }
+        },
+    };
+    entry.inputs = inputs.clone().unwrap_or(Value::Null);
+
+    if !config.command_enabled(command.name) {
+        entry.deny("command is not enabled");
+        return;
+    }
+    let workflow = config.workflow_file(command.name);
+    entry.workflow = Some(workflow.clone());
+
+    let login = source.user.as_str();
+    if !policy::allowed(
+        app,
+        &config.permissions,
+        source,
+        config::workflow_name(&workflow),
+    )
+    .await
+    {
+        entry.deny("not allowed by policy");
+        return;
+    }
+
+    let reply_url = source.reply_url.as_str();
+    let rules = config
+        .permissions
+        .for_workflow(config::workflow_name(&workflow))
+        .unwrap();
+
+    // Get pull request info, issues have none and dispatch on the default branch
+    let pull = match source.pull_url.as_deref() {
+        None if rules.approvals > 0 => {
+            let body = format!(
+                "@{login} `{workflow}` needs approving reviews, so it only runs on pull requests."
+            );
+            api::comment(app, reply_url, &body).await;
+            entry.deny("needs reviews");
+            return;
+        }
+        None => None,
+        Some(pull_url) => {
+            let pull = match api::get_json(app, pull_url).await {
+                None => {
+                    tracing::error!("Pull request api error '{pull_url}'");
+                    entry.allow("could not get pull request");
+                    return;
+                }
+                Some(pull) => pull,
+            };
+
+            if let Err(missing) = policy::reviews_satisfied(app, rules, &pull).await {
+                let body = format!("@{login} `{workflow}` was not run. {missing}");
+                api::comment(app, reply_url, &body).await;
+                entry.deny(missing);
+                return;
+            }
+            Some(pull)
+        }
+    };
+
+    let mut inputs = config.workflow_inputs(command.name, inputs);
+
+    // Track before dispatching, the run can be requested before the dispatch call returns
+    let mut tracking = None;
+    if let Some(input) = &config.dispatch.id_input {
+        let id = runs::new_id(source.id);
+        let tracked = Tracked {
+            id: id.clone(),
+            repo: entry.repo.clone(),
+            number: entry.number,
+            workflow: workflow.clone(),
+            comment_id: source.id,
+            comments_url: source.comments_url.clone(),
+            reactions_url: source.reactions_url.clone(),
+            run_id: None,
+            sha: pull
+                .as_ref()
+                .and_then(|p| dispatch::pull_target(config.dispatch.mode, p))
+                .map(|(_, sha)| sha),
+        };
+
+        if let Err(active) = runs::claim(pool, &tracked, command.flag("force")).await {
+            let link = active
+                .html_url
+                .as_deref()
+                .unwrap_or("(waiting for the run to start)");
+            let body = format!(
+                "`{workflow}` is already running for #{}: {link}\n\
+                Comment `{} {} --force` to run it again anyway.",
+                source.number, config.prefix, command.name
+            );
+            api::comment(app, reply_url, &body).await;
+            entry.deny("already running");
+            return;
+        }
+
+        inputs.insert(input.clone(), Value::String(id));
+        tracking = Some(tracked);
+    }
+
+    // Only dispatches that would go ahead use up the budget
+    if config.features.rate_limit {
+        let limit = config.rate_limit.for_workflow(&workflow);
+        if let Err(wait) = ratelimit::take(pool, source.repo_name(), login, &workflow, limit).await
+        {
+            if let Some(tracked) = &tracking {
+                runs::release(pool, tracked).await;
+            }
+            let body = format!(
+                "@{login} `{workflow}` was run too often, try again in {} minute(s).",
+                wait.div_ceil(60)
+            );
+            api::comment(app, reply_url, &body).await;
+            entry.deny("rate limited");
+            return;
+        }
+    }
+    if let Some(tracked) = &tracking {
+        runs::track(pool, &tracked.id, tracked).await;
+    }
+    entry.inputs = Value::Object(inputs.clone());
+
+    let dispatched = match &pull {
+        Some(pull) => dispatch::pull_request(app, &config.dispatch, pull, &workflow, inputs).await,
+        None => {
+            let repo_url = source.repo["url"].as_str().unwrap();
+            let branch = source.repo["default_branch"].as_str().unwrap();
+            dispatch::run(app, repo_url, &workflow, branch, inputs).await
+        }
+    };
+    if dispatched {
+        if let Some(reactions_url) = &source.reactions_url {
+            api::react(app, reactions_url, "rocket").await;
+        }
+        entry.allow("dispatched");
+    }
+    else {
+        if let Some(tracked) = tracking {
+            runs::release(pool, &tracked).await;
+        }
+        entry.allow("dispatch failed");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(slash: bool) -> RepoConfig {
+        RepoConfig {
+            slash,
+            ..RepoConfig::default()
+        }
+    }
+
+    #[test]
+    fn parse_prefix() {
+        let command = parse(&config(false), "  !harmful test2 {\"a\": 1}  ").unwrap();
+        assert_eq!(command.name, "test2");
+        assert!(command.flags.is_empty());
+        assert_eq!(command.args, Some("{\"a\": 1}"));
+
+        assert_eq!(parse(&config(false), "!harmful").map(|c| c.name), None);
+        assert_eq!(parse(&config(false), "!harmfultest2"), None);
+        assert_eq!(parse(&config(false), "please !harmful test2"), None);
+    }
+
+    #[test]
+    fn parse_slash() {
+        assert_eq!(parse(&config(false), "/test2"), None);
+        let command = parse(&config(true), "/test2").unwrap();
+        assert_eq!(command.name, "test2");
+        assert_eq!(command.args, None);
+        assert_eq!(parse(&config(true), "/ test2"), None);
+        assert_eq!(
+            parse(&config(true), "!harmful test2").unwrap().name,
+            "test2"
+        );
+    }
+
+    #[test]
+    fn parse_flags() {
+        let command = parse(&config(true), "/unlock --force --all  prod").unwrap();
+        assert_eq!(command.flags, vec!["force", "all"]);
+        assert!(command.flag("force"));
+        assert!(!command.flag("cancel"));
+        assert_eq!(command.args, Some("prod"));
+
+        // Flags only come right after the name
+        let command = parse(&config(false), "!harmful backport v1 --force").unwrap();
+        assert!(command.flags.is_empty());
+        assert_eq!(command.args, Some("v1 --force"));
+    }
+}
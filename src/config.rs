@@ -0,0 +1,601 @@
+use crate::{
+    api,
+    policy::{Policy, RepoPolicy, WorkflowPolicy, POLICY},
+    states::{AppState, ConnectionPool},
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use bb8_redis::redis::cmd;
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+
+pub static CONFIG_PATH: &str = ".github/harmful.toml";
+static CONFIG_EXPIRE: usize = 3600;
+
+/// Per repository config, read from `.github/harmful.toml` on the default branch.
+/// Repositories without one use the file in the org's `.github` repository.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RepoConfig {
+    /// Like `!harmful` or `/harmful`.
+    pub prefix: String,
+    /// Also accept `/command` without the prefix.
+    pub slash: bool,
+    /// Commands that may be used, empty allows every command.
+    pub commands: Vec<String>,
+    pub aliases: HashMap<String, Alias>,
+    /// Inputs sent with every dispatch, keyed by workflow name.
+    pub inputs: HashMap<String, Map<String, Value>>,
+    /// Layered over `COMMAND_POLICY`, see `Policy::layer`.
+    #[serde(
+        default = "default_permissions",
+        deserialize_with = "layered_permissions"
+    )]
+    pub permissions: Policy,
+    pub dispatch: Dispatch,
+    pub rate_limit: RateLimits,
+    pub label_triggers: LabelTriggers,
+    pub deploy: Deploy,
+    pub merge: Merge,
+    pub update: Update,
+    pub triage: Triage,
+    pub auto_label: AutoLabel,
+    pub size: Size,
+    pub conventional: Conventional,
+    pub changelog: Changelog,
+    pub release_notes: ReleaseNotes,
+    pub features: Features,
+}
+impl RepoConfig {
+    pub fn command_enabled(&self, command: &str) -> bool {
+        self.commands.is_empty() || self.commands.iter().any(|c| c.eq(command))
+    }
+
+    /// Resolve a command name to the workflow file it dispatches.
+    pub fn workflow_file(&self, command: &str) -> String {
+        match self.aliases.get(command) {
+            Some(Alias::Workflow(file)) => file.clone(),
+            Some(Alias::Preset { workflow, .. }) => workflow.clone(),
+            None if command.ends_with(".yml") || command.ends_with(".yaml") => command.to_string(),
+            None => format!("{command}.yml"),
+        }
+    }
+
+    /// Inputs for a command's workflow.
+    /// Defaults for the workflow are overridden by alias presets, then by inputs given with the command.
+    pub fn workflow_inputs(&self, command: &str, given: Option<Value>) -> Map<String, Value> {
+        let workflow = self.workflow_file(command);
+        let mut inputs = self
+            .inputs
+            .get(workflow_name(&workflow))
+            .cloned()
+            .unwrap_or_default();
+        if let Some(Alias::Preset { inputs: preset, .. }) = self.aliases.get(command) {
+            inputs.extend(preset.clone());
+        }
+        if let Some(Value::Object(given)) = given {
+            inputs.extend(given);
+        }
+        inputs
+    }
+}
+impl Default for RepoConfig {
+    fn default() -> Self {
+        Self {
+            prefix: "!harmful".to_string(),
+            slash: false,
+            commands: Vec::new(),
+            aliases: HashMap::new(),
+            inputs: HashMap::new(),
+            permissions: default_permissions(),
+            dispatch: Dispatch::default(),
+            rate_limit: RateLimits::default(),
+            label_triggers: LabelTriggers::default(),
+            deploy: Deploy::default(),
+            merge: Merge::default(),
+            update: Update::default(),
+            triage: Triage::default(),
+            auto_label: AutoLabel::default(),
+            size: Size::default(),
+            conventional: Conventional::default(),
+            changelog: Changelog::default(),
+            release_notes: ReleaseNotes::default(),
+            features: Features::default(),
+        }
+    }
+}
+
+/// A command name for a workflow file, optionally with preset inputs.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum Alias {
+    Workflow(String),
+    Preset {
+        workflow: String,
+        #[serde(default)]
+        inputs: Map<String, Value>,
+    },
+}
+
+/// How workflows are dispatched for pull requests.
+/// They always run from the default branch, the inputs say what to check out.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Dispatch {
+    #[serde(rename = "ref")]
+    pub mode: RefMode,
+    /// Workflow input that gets the ref to check out, picked by `ref`.
+    /// Workflows have to declare it, so it is off unless set.
+    pub ref_input: Option<String>,
+    /// Workflow input that gets the commit sha the run is for.
+    /// Workflows have to declare it, so it is off unless set.
+    pub sha_input: Option<String>,
+    /// Workflow input that gets a correlation id, like `harmful_id`.
+    /// Workflows need it in their `run-name` for the bot to follow the run.
+    pub id_input: Option<String>,
+}
+
+/// Which ref of a pull request a workflow is given to check out.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RefMode {
+    /// The head branch, or `refs/pull/{n}/head` for forks.
+    #[default]
+    Branch,
+    /// `refs/pull/{n}/merge`
+    Merge,
+    /// `refs/pull/{n}/head`
+    Head,
+}
+
+/// Rate limits for commands, per user, repository and workflow.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct RateLimits {
+    /// Used for any workflow without its own entry.
+    pub default: Limit,
+    pub workflows: HashMap<String, Limit>,
+}
+impl RateLimits {
+    pub fn for_workflow(&self, workflow: &str) -> &Limit {
+        self.workflows
+            .get(workflow_name(workflow))
+            .unwrap_or(&self.default)
+    }
+}
+
+/// A token bucket, `burst = 1` makes it a cooldown of one run per `3600 / per_hour` seconds.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Limit {
+    /// Runs that can be started back to back.
+    pub burst: u32,
+    /// Runs given back per hour, 0 turns the limit off.
+    pub per_hour: u32,
+}
+impl Default for Limit {
+    fn default() -> Self {
+        Self {
+            burst: 5,
+            per_hour: 20,
+        }
+    }
+}
+
+/// Labels that dispatch a workflow when added to a pull request.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct LabelTriggers {
+    /// Label to command name, aliases work like in comments.
+    pub workflows: HashMap<String, String>,
+    /// Take the label off again, so adding it again runs the workflow again.
+    pub remove: bool,
+}
+
+/// Environments for the `deploy` command.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Deploy {
+    pub environments: HashMap<String, Environment>,
+    /// How long a `lock` holds an environment when no hours are given.
+    pub lock_hours: u32,
+    /// Longest a `lock` may hold an environment.
+    pub max_lock_hours: u32,
+}
+impl Default for Deploy {
+    fn default() -> Self {
+        Self {
+            environments: HashMap::new(),
+            lock_hours: 24,
+            max_lock_hours: 168,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Environment {
+    /// Workflow dispatched for a deployment from the default branch, it gets `environment` and `deployment_id` inputs
+    /// and the head commit in `dispatch.sha_input`.
+    pub workflow: String,
+    #[serde(default)]
+    pub inputs: Map<String, Value>,
+    /// Who may deploy, the workflow's permissions are used when not set.
+    pub permissions: Option<WorkflowPolicy>,
+}
+
+/// Settings for the `merge` and `automerge` commands.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Merge {
+    /// Used when the command does not name a method.
+    pub method: MergeMethod,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MergeMethod {
+    #[default]
+    Merge,
+    Squash,
+    Rebase,
+}
+
+/// Settings for keeping pull requests up to date with their base branch.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Update {
+    /// Open pull requests with this label get the base branch merged in whenever it is pushed to.
+    pub label: Option<String>,
+}
+
+/// What the triage commands may touch, nothing when a list is empty.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Triage {
+    /// Labels for `label` and `unlabel`.
+    pub labels: Vec<String>,
+    /// Users for `assign`, besides the commenter.
+    pub assignees: Vec<String>,
+    /// Users and `org/team` teams for `request-review`.
+    pub reviewers: Vec<String>,
+    /// Repositories for `transfer`, as `owner/repo`.
+    pub transfer_to: Vec<String>,
+}
+
+/// Labels for pull requests by the paths they change.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct AutoLabel {
+    /// Label to globs, like `"area/docs" = ["docs/**", "*.md"]`.
+    pub rules: HashMap<String, Vec<String>>,
+    /// Take labels from the rules off again when no changed file matches them anymore.
+    pub remove_stale: bool,
+}
+impl Default for AutoLabel {
+    fn default() -> Self {
+        Self {
+            rules: HashMap::new(),
+            remove_stale: true,
+        }
+    }
+}
+
+/// Size labels and check for pull requests, counted in changed lines.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Size {
+    /// Upper bounds for XS, S, M and L, anything bigger is XL.
+    pub limits: Vec<u64>,
+    pub label_prefix: String,
+    /// Fail the check above this many lines.
+    pub fail_above: Option<u64>,
+    /// Label that lets a pull request over `fail_above` pass.
+    pub override_label: String,
+    /// Globs for generated files, which are not counted.
+    pub exclude: Vec<String>,
+}
+impl Default for Size {
+    fn default() -> Self {
+        Self {
+            limits: vec![10, 30, 100, 500],
+            label_prefix: "size/".to_string(),
+            fail_above: None,
+            override_label: "size/override".to_string(),
+            exclude: Vec::new(),
+        }
+    }
+}
+
+/// Conventional Commits check for pull request titles and commits.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Conventional {
+    pub types: Vec<String>,
+    /// Scopes that may be used, empty allows any scope.
+    pub scopes: Vec<String>,
+    /// Check every commit message too, not only the title.
+    pub commits: bool,
+}
+impl Default for Conventional {
+    fn default() -> Self {
+        Self {
+            types: [
+                "feat", "fix", "docs", "style", "refactor", "perf", "test", "build", "ci", "chore",
+                "revert",
+            ]
+            .map(str::to_string)
+            .to_vec(),
+            scopes: Vec::new(),
+            commits: false,
+        }
+    }
+}
+
+/// Changelog check for pull requests.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Changelog {
+    /// Globs of changelog files, changing any of them passes the check.
+    pub paths: Vec<String>,
+    /// Label that passes the check without a changelog entry.
+    pub skip_label: String,
+    /// Path of the contributing guide on the default branch, linked from the check.
+    pub contributing: String,
+}
+impl Default for Changelog {
+    fn default() -> Self {
+        Self {
+            paths: vec!["CHANGELOG.md".to_string()],
+            skip_label: "skip-changelog".to_string(),
+            contributing: "CONTRIBUTING.md".to_string(),
+        }
+    }
+}
+
+/// Draft release notes from the pull requests merged since the last release.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ReleaseNotes {
+    /// Sections of the notes, a pull request goes in the first one with any of its labels.
+    pub categories: Vec<Category>,
+    /// Section for pull requests in no category, empty leaves them out.
+    pub other: String,
+    /// Pull requests with any of these labels are left out.
+    pub exclude_labels: Vec<String>,
+    /// Line for each pull request, with `$TITLE`, `$NUMBER` and `$AUTHOR`.
+    pub change_template: String,
+    /// Body of the release, with `$CHANGES`.
+    pub template: String,
+    /// Used for `$CHANGES` when nothing was merged.
+    pub no_changes: String,
+    /// Tag and name of the draft release the notes go in, the tag is made when it is published
+    /// so it should be renamed to the version then.
+    pub draft_tag: String,
+    pub draft_name: String,
+}
+impl Default for ReleaseNotes {
+    fn default() -> Self {
+        Self {
+            categories: Vec::new(),
+            other: "Changes".to_string(),
+            exclude_labels: Vec::new(),
+            change_template: "- $TITLE (#$NUMBER) @$AUTHOR".to_string(),
+            template: "## What's changed\n\n$CHANGES".to_string(),
+            no_changes: "No changes.".to_string(),
+            draft_tag: "unreleased".to_string(),
+            draft_name: "Unreleased".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Category {
+    pub title: String,
+    pub labels: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Features {
+    /// Comment commands.
+    pub commands: bool,
+    /// Check runs for workflows the bot dispatched.
+    pub check_runs: bool,
+    /// One comment per pull request with the progress of tracked runs, needs `dispatch.id_input`.
+    pub status_comment: bool,
+    pub rate_limit: bool,
+    pub label_triggers: bool,
+    pub auto_label: bool,
+    /// Size labels and check, off unless turned on since it labels every pull request.
+    pub size_check: bool,
+    /// Conventional Commits check, off unless turned on.
+    pub conventional: bool,
+    /// DCO sign-off check, off unless turned on.
+    pub dco: bool,
+    /// Changelog check, off unless turned on.
+    pub changelog: bool,
+    /// Draft release notes, off unless turned on.
+    pub release_notes: bool,
+}
+impl Default for Features {
+    fn default() -> Self {
+        Self {
+            commands: true,
+            check_runs: true,
+            status_comment: true,
+            rate_limit: true,
+            label_triggers: true,
+            auto_label: true,
+            size_check: false,
+            conventional: false,
+            dco: false,
+            changelog: false,
+            release_notes: false,
+        }
+    }
+}
+
+fn default_permissions() -> Policy {
+    POLICY.clone()
+}
+
+fn layered_permissions<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Policy, D::Error> {
+    RepoPolicy::deserialize(deserializer).map(|repo| POLICY.layer(repo))
+}
+
+/// Workflow file name without the extension, used to key per workflow settings.
+pub fn workflow_name(workflow: &str) -> &str {
+    workflow
+        .strip_suffix(".yml")
+        .or_else(|| workflow.strip_suffix(".yaml"))
+        .unwrap_or(workflow)
+}
+
+/// Load the config for a repository, `repo` being the repository object from a webhook payload.
+/// None if the config could not be read or parsed, so its restrictions are never silently dropped.
+pub async fn load(app: &AppState, pool: &ConnectionPool, repo: &Value) -> Option<RepoConfig> {
+    let full_name = repo["full_name"].as_str().unwrap();
+    let owner = repo["owner"]["login"].as_str().unwrap();
+
+    let mut source = cached_source(app, pool, full_name).await.ok()?;
+    let org_repo = format!("{owner}/.github");
+    if source.is_none() && !full_name.eq(&org_repo) {
+        source = cached_source(app, pool, &org_repo).await.ok()?;
+    }
+
+    match source {
+        None => Some(RepoConfig::default()),
+        Some(source) => match toml::from_str(&source) {
+            Ok(config) => Some(config),
+            Err(err) => {
+                tracing::error!("Bad config for {full_name}: {err}");
+                None
+            }
+        },
+    }
+}
+
+/// Drop the cached config when a push to the default branch touches it.
+pub async fn invalidate(pool: &ConnectionPool, payload: &Value) {
+    let repo = &payload["repository"];
+    let default_ref = format!("refs/heads/{}", repo["default_branch"].as_str().unwrap());
+    if !payload["ref"].as_str().unwrap().eq(&default_ref) {
+        return;
+    }
+
+    let touched = payload["commits"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .flat_map(|commit| ["added", "modified", "removed"].map(|k| &commit[k]))
+        .filter_map(|files| files.as_array())
+        .flatten()
+        .any(|file| file.as_str().eq(&Some(CONFIG_PATH)));
+    if touched {
+        let full_name = repo["full_name"].as_str().unwrap();
+        let mut conn = pool.as_ref().unwrap().get().await.unwrap();
+        let _: usize = cmd("DEL")
+            .arg(format!("config.{full_name}"))
+            .query_async(&mut *conn)
+            .await
+            .unwrap();
+        tracing::info!("Config changed for {full_name}, cleared cache.");
+    }
+}
+
+/// Config file contents, an empty string in the store marks a repository without one.
+/// Failed reads are not cached.
+async fn cached_source(
+    app: &AppState,
+    pool: &ConnectionPool,
+    full_name: &str,
+) -> Result<Option<String>, ()> {
+    let key = format!("config.{full_name}");
+    let pool = pool.as_ref().unwrap();
+
+    let cached: Option<String> = {
+        let mut conn = pool.get().await.unwrap();
+        cmd("GET").arg(&key).query_async(&mut *conn).await.unwrap()
+    };
+    if let Some(cached) = cached {
+        return Ok((!cached.is_empty()).then_some(cached));
+    }
+
+    let source = fetch_source(app, full_name).await.map_err(|_| {
+        tracing::error!("Could not read the config for {full_name}");
+    })?;
+    let mut conn = pool.get().await.unwrap();
+    let reply: String = cmd("SET")
+        .arg(&key)
+        .arg(source.as_deref().unwrap_or(""))
+        .arg("EX")
+        .arg(CONFIG_EXPIRE)
+        .query_async(&mut *conn)
+        .await
+        .unwrap();
+    tracing::debug!("Cached config for {full_name}: {reply}");
+
+    Ok(source)
+}
+
+/// Ok(None) if the repository has no config file.
+async fn fetch_source(app: &AppState, full_name: &str) -> Result<Option<String>, ()> {
+    let url = format!("https://api.github.com/repos/{full_name}/contents/{CONFIG_PATH}");
+    let json = match api::get_optional(app, &url).await? {
+        None => return Ok(None),
+        Some(json) => json,
+    };
+    // Files over 1 MB come without content
+    let content: String = json["content"]
+        .as_str()
+        .ok_or(())?
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .collect();
+    let content = STANDARD.decode(content).map_err(|_| ())?;
+    String::from_utf8(content).map(Some).map_err(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn workflow_inputs_override_in_order() {
+        let config: RepoConfig = toml::from_str(
+            r#"
+            [aliases]
+            ci = "test2.yml"
+            echo = { workflow = "test2.yml", inputs = { a = "preset", b = "preset" } }
+
+            [inputs.test2]
+            a = "default"
+            b = "default"
+            c = "default"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.workflow_file("ci"), "test2.yml");
+        assert_eq!(config.workflow_file("echo"), "test2.yml");
+        assert_eq!(config.workflow_file("lint"), "lint.yml");
+        assert_eq!(config.workflow_file("lint.yaml"), "lint.yaml");
+
+        let inputs = config.workflow_inputs("echo", Some(serde_json::json!({ "b": "given" })));
+        assert_eq!(inputs["a"], "preset");
+        assert_eq!(inputs["b"], "given");
+        assert_eq!(inputs["c"], "default");
+        assert!(config.workflow_inputs("lint", None).is_empty());
+    }
+
+    #[test]
+    fn permissions_keep_the_global_policy() {
+        let config: RepoConfig = toml::from_str("[permissions]\ndeny = [\"danger\"]\n").unwrap();
+        assert_eq!(
+            config.permissions.default.associations,
+            POLICY.default.associations
+        );
+        assert!(config.permissions.for_workflow("danger").is_none());
+        assert!(config.permissions.for_workflow("ci").is_some());
+    }
+}
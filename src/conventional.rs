@@ -0,0 +1,220 @@
+use crate::{
+    api, checks,
+    config::{self, Conventional},
+    states::{AppState, ConnectionPool},
+};
+use serde_json::{json, Value};
+
+static CHECK_NAME: &str = "conventional commits";
+
+/// Check the pull request title, and with `conventional.commits` every commit message, against Conventional Commits.
+pub async fn pull_request(app: &AppState, pool: &ConnectionPool, payload: &Value) {
+    match payload["action"].as_str().unwrap() {
+        "opened" | "synchronize" | "reopened" => {}
+        "edited" if !payload["changes"]["title"].is_null() => {}
+        _ => return,
+    }
+
+    let config = match config::load(app, pool, &payload["repository"]).await {
+        None => return,
+        Some(config) => config,
+    };
+    if !config.features.conventional {
+        return;
+    }
+    let settings = &config.conventional;
+
+    let pull = &payload["pull_request"];
+    let title = pull["title"].as_str().unwrap();
+    let mut problems = Vec::new();
+    if let Err(problem) = check(settings, title) {
+        problems.push(format!("Title: {problem}"));
+    }
+
+    let repo_url = payload["repository"]["url"].as_str().unwrap();
+    let mut annotations = Vec::new();
+    if settings.commits {
+        let pull_url = pull["url"].as_str().unwrap();
+        let commits = match api::get_all(app, &format!("{pull_url}/commits")).await {
+            None => return,
+            Some(commits) => commits,
+        };
+        // Annotations have to point at a file at the head commit, like one the pull request still changes
+        let files: Vec<String> = api::get_all(app, &format!("{pull_url}/files"))
+            .await
+            .unwrap_or_default()
+            .iter()
+            .filter(|f| !f["status"].as_str().eq(&Some("removed")))
+            .filter_map(|f| f["filename"].as_str().map(str::to_string))
+            .collect();
+
+        // Merge commits are made by git or GitHub, not written
+        for commit in commits
+            .iter()
+            .filter(|c| c["parents"].as_array().is_some_and(|p| p.len() < 2))
+        {
+            let sha = commit["sha"].as_str().unwrap();
+            let message = commit["commit"]["message"].as_str().unwrap_or_default();
+            if let Err(problem) = check(settings, message) {
+                problems.push(format!("Commit `{}`: {problem}", &sha[..7]));
+
+                // The api takes at most 50 annotations per request
+                if annotations.len() == 50 {
+                    continue;
+                }
+                if let Some(path) = annotation_path(app, repo_url, sha, &files).await {
+                    let header = message.lines().next().unwrap_or_default();
+                    annotations.push(json!({
+                        "path": path,
+                        "start_line": 1,
+                        "end_line": 1,
+                        "annotation_level": "failure",
+                        "title": format!("Commit {}", &sha[..7]),
+                        "message": format!("{header}\n\n{problem}"),
+                    }));
+                }
+            }
+        }
+    }
+
+    let (title, summary) = if problems.is_empty() {
+        (
+            "Follows Conventional Commits".to_string(),
+            "Everything checked follows Conventional Commits.".to_string(),
+        )
+    }
+    else {
+        (
+            format!("{} problem(s)", problems.len()),
+            format!(
+                "Use `type(scope): description`, with types {}.\n\n{}",
+                settings
+                    .types
+                    .iter()
+                    .map(|t| format!("`{t}`"))
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                problems
+                    .iter()
+                    .map(|p| format!("- {p}"))
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            ),
+        )
+    };
+
+    let output = json!({ "title": title, "summary": summary, "annotations": annotations });
+    let head_sha = pull["head"]["sha"].as_str().unwrap();
+    checks::complete(
+        app,
+        repo_url,
+        head_sha,
+        CHECK_NAME,
+        problems.is_empty(),
+        output,
+    )
+    .await;
+}
+
+/// The first file a commit changed that the pull request still changes, or else the pull request's first file.
+/// None when the pull request has no files left to point at, the commit is only listed in the summary then.
+async fn annotation_path(
+    app: &AppState,
+    repo_url: &str,
+    sha: &str,
+    files: &[String],
+) -> Option<String> {
+    let commit = api::get_json(app, &format!("{repo_url}/commits/{sha}")).await;
+    let changed = commit
+        .as_ref()
+        .and_then(|c| c["files"].as_array())
+        .into_iter()
+        .flatten()
+        .filter_map(|f| f["filename"].as_str());
+    for path in changed {
+        if files.iter().any(|f| f.eq(path)) {
+            return Some(path.to_string());
+        }
+    }
+    files.first().cloned()
+}
+
+/// Check the first line of a message, like `feat(api)!: add a thing`.
+fn check(settings: &Conventional, message: &str) -> Result<(), String> {
+    let header = message.lines().next().unwrap_or_default();
+    let (prefix, description) = header
+        .split_once(": ")
+        .ok_or_else(|| format!("`{header}` is not `type(scope): description`"))?;
+    if description.trim().is_empty() {
+        return Err("the description is empty".to_string());
+    }
+
+    let prefix = prefix.strip_suffix('!').unwrap_or(prefix);
+    let (kind, scope) = match prefix.split_once('(') {
+        None => (prefix, None),
+        Some((kind, rest)) => {
+            let scope = rest
+                .strip_suffix(')')
+                .ok_or_else(|| format!("`{prefix}` does not close its scope"))?;
+            (kind, Some(scope))
+        }
+    };
+
+    if !settings.types.iter().any(|t| t.eq(kind)) {
+        return Err(format!("`{kind}` is not an allowed type"));
+    }
+    match scope {
+        Some("") => Err("the scope is empty".to_string()),
+        Some(scope)
+            if !settings.scopes.is_empty() && !settings.scopes.iter().any(|s| s.eq(scope)) =>
+        {
+            Err(format!("`{scope}` is not an allowed scope"))
+        }
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(scopes: &[&str]) -> Conventional {
+        Conventional {
+            scopes: scopes.iter().map(|s| s.to_string()).collect(),
+            ..Conventional::default()
+        }
+    }
+
+    #[test]
+    fn accepts_headers() {
+        let any = settings(&[]);
+        assert_eq!(check(&any, "feat: add a thing"), Ok(()));
+        assert_eq!(
+            check(&any, "fix(api)!: drop v1\n\nBREAKING CHANGE: gone"),
+            Ok(())
+        );
+        assert_eq!(check(&any, "chore!: bump"), Ok(()));
+    }
+
+    #[test]
+    fn refuses_malformed_headers() {
+        let any = settings(&[]);
+        assert!(check(&any, "add a thing").is_err());
+        assert!(check(&any, "feat:add a thing").is_err());
+        assert!(check(&any, "feat: ").is_err());
+        assert!(check(&any, "feature: add a thing").is_err());
+        assert!(check(&any, "feat(api: add a thing").is_err());
+        assert!(check(&any, "feat(): add a thing").is_err());
+    }
+
+    #[test]
+    fn scopes_are_checked_when_listed() {
+        let listed = settings(&["api"]);
+        assert_eq!(check(&listed, "feat(api): add a thing"), Ok(()));
+        assert_eq!(check(&listed, "feat: add a thing"), Ok(()));
+        assert_eq!(
+            check(&listed, "feat(web): add a thing"),
+            Err("`web` is not an allowed scope".to_string())
+        );
+    }
+}
@@ -0,0 +1,191 @@
+use crate::{
+    api, audit, checks, config,
+    sources::Source,
+    states::{AppState, ConnectionPool},
+};
+use bb8_redis::redis::cmd;
+use serde_json::{json, Value};
+
+static CHECK_NAME: &str = "DCO";
+static OVERRIDE_EXPIRE: usize = 2592000;
+
+fn override_key(repo: &str, number: u64) -> String {
+    format!("dco_override.{repo}.{number}")
+}
+
+/// Check that every commit of a pull request is signed off by its author.
+pub async fn pull_request(app: &AppState, pool: &ConnectionPool, payload: &Value) {
+    if !matches!(
+        payload["action"].as_str().unwrap(),
+        "opened" | "synchronize" | "reopened"
+    ) {
+        return;
+    }
+
+    let config = match config::load(app, pool, &payload["repository"]).await {
+        None => return,
+        Some(config) => config,
+    };
+    if !config.features.dco {
+        return;
+    }
+
+    let repo = &payload["repository"];
+    let repo_url = repo["url"].as_str().unwrap();
+    let pull = &payload["pull_request"];
+    let number = pull["number"].as_u64().unwrap();
+    let head_sha = pull["head"]["sha"].as_str().unwrap();
+
+    // An override holds until new commits are pushed
+    let overridden: Option<String> = {
+        let mut conn = pool.as_ref().unwrap().get().await.unwrap();
+        cmd("GET")
+            .arg(override_key(repo["full_name"].as_str().unwrap(), number))
+            .query_async(&mut *conn)
+            .await
+            .unwrap()
+    };
+    if overridden.is_some_and(|sha| sha.eq(head_sha)) {
+        return;
+    }
+
+    let pull_url = pull["url"].as_str().unwrap();
+    let commits = match api::get_all(app, &format!("{pull_url}/commits")).await {
+        None => return,
+        Some(commits) => commits,
+    };
+    let missing: Vec<(&str, String)> = commits
+        .iter()
+        .filter(|c| c["parents"].as_array().is_some_and(|p| p.len() < 2))
+        .filter(|c| !signed_off(&c["commit"]))
+        .map(|c| {
+            let author = &c["commit"]["author"];
+            (
+                c["sha"].as_str().unwrap(),
+                format!(
+                    "{} <{}>",
+                    author["name"].as_str().unwrap_or_default(),
+                    author["email"].as_str().unwrap_or_default()
+                ),
+            )
+        })
+        .collect();
+
+    let output = if missing.is_empty() {
+        json!({
+            "title": "All commits are signed off",
+            "summary": format!("{} commit(s) have a `Signed-off-by` from their author.", commits.len()),
+        })
+    }
+    else {
+        let rows = missing
+            .iter()
+            .map(|(sha, author)| format!("| `{}` | {} |", &sha[..7], author.replace('|', "\\|")))
+            .collect::<Vec<_>>()
+            .join("\n");
+        json!({
+            "title": format!("{} commit(s) are not signed off", missing.len()),
+            "summary": format!(
+                "Every commit needs a `Signed-off-by: Name <email>` line matching its author, \
+                certifying the [Developer Certificate of Origin](https://developercertificate.org/).\n\n\
+                | Commit | Author |\n| --- | --- |\n{rows}\n\n\
+                To sign off the commits of this pull request, rebase them with sign-offs and force push:\n\n\
+                ```\ngit rebase --signoff HEAD~{}\ngit push --force-with-lease\n```",
+                commits.len()
+            ),
+        })
+    };
+    checks::complete(
+        app,
+        repo_url,
+        head_sha,
+        CHECK_NAME,
+        missing.is_empty(),
+        output,
+    )
+    .await;
+}
+
+/// Whether a commit message has a `Signed-off-by` trailer for the commit's author.
+fn signed_off(commit: &Value) -> bool {
+    let name = commit["author"]["name"].as_str().unwrap_or_default();
+    let email = commit["author"]["email"].as_str().unwrap_or_default();
+    commit["message"]
+        .as_str()
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|l| l.trim().strip_prefix("Signed-off-by:"))
+        .filter_map(|s| s.trim().strip_suffix('>')?.split_once('<'))
+        .any(|(n, e)| n.trim().eq_ignore_ascii_case(name) && e.trim().eq_ignore_ascii_case(email))
+}
+
+/// `dco-override`: pass the DCO check for the pull request's current commits.
+pub async fn command(
+    app: &AppState,
+    pool: &ConnectionPool,
+    source: &Source,
+    pull: &Value,
+    entry: &mut audit::Entry,
+) {
+    let login = source.user.as_str();
+    let head_sha = pull["head"]["sha"].as_str().unwrap();
+    entry.inputs = json!({ "sha": head_sha });
+
+    let mut conn = pool.as_ref().unwrap().get().await.unwrap();
+    let _: String = cmd("SET")
+        .arg(override_key(source.repo_name(), source.number))
+        .arg(head_sha)
+        .arg("EX")
+        .arg(OVERRIDE_EXPIRE)
+        .query_async(&mut *conn)
+        .await
+        .unwrap();
+
+    let output = json!({
+        "title": "Overridden",
+        "summary": format!("@{login} let these commits through without sign-offs."),
+    });
+    let repo_url = source.repo["url"].as_str().unwrap();
+    checks::complete(app, repo_url, head_sha, CHECK_NAME, true, output).await;
+
+    if let Some(reactions_url) = &source.reactions_url {
+        api::react(app, reactions_url, "+1").await;
+    }
+    entry.allow("overridden");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn commit(message: &str) -> Value {
+        json!({
+            "author": { "name": "Ada Lovelace", "email": "ada@example.com" },
+            "message": message,
+        })
+    }
+
+    #[test]
+    fn matches_author_trailer() {
+        assert!(signed_off(&commit(
+            "Add a thing\n\nSigned-off-by: Ada Lovelace <ada@example.com>"
+        )));
+        assert!(signed_off(&commit(
+            "Add a thing\n\nSigned-off-by: Someone Else <else@example.com>\nSigned-off-by: ada lovelace <ADA@example.com>"
+        )));
+    }
+
+    #[test]
+    fn refuses_other_trailers() {
+        assert!(!signed_off(&commit("Add a thing")));
+        assert!(!signed_off(&commit(
+            "Add a thing\n\nSigned-off-by: Ada Lovelace <ada@other.com>"
+        )));
+        assert!(!signed_off(&commit(
+            "Add a thing\n\nSigned-off-by: Ada Lovelace ada@example.com"
+        )));
+        assert!(!signed_off(&commit(
+            "Add a thing\n\nReviewed-by: Ada Lovelace <ada@example.com>"
+        )));
+    }
+}
@@ -0,0 +1,238 @@
+use crate::{
+    api, audit,
+    commands::Command,
+    config::{self, Environment, RepoConfig},
+    dispatch, locks,
+    policy::{self, WorkflowPolicy},
+    sources::Source,
+    states::{AppState, ConnectionPool},
+    status,
+};
+use bb8_redis::redis::cmd;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+static DEPLOY_EXPIRE: usize = 86400;
+
+/// A deployment started from a command, waiting on its statuses.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct TrackedDeploy {
+    environment: String,
+    /// Api url of the bot's comment about the deployment.
+    comment_url: String,
+    user: String,
+}
+
+/// `deploy <environment>`: create a deployment for the pull request's head and dispatch its workflow.
+pub async fn command(
+    app: &AppState,
+    pool: &ConnectionPool,
+    config: &RepoConfig,
+    source: &Source,
+    command: &Command<'_>,
+    pull: &Value,
+    entry: &mut audit::Entry,
+) {
+    let requested = command.args.and_then(|a| a.split_whitespace().next());
+    let (name, environment) =
+        match requested.and_then(|e| config.deploy.environments.get_key_value(e)) {
+            None => {
+                let mut names: Vec<_> = config
+                    .deploy
+                    .environments
+                    .keys()
+                    .map(|k| format!("`{k}`"))
+                    .collect();
+                names.sort();
+                let body = format!(
+                    "Usage: `{} deploy <environment>`, environments are {}.",
+                    config.prefix,
+                    if names.is_empty() { "not set up".to_string() } else { names.join(", ") }
+                );
+                api::comment(app, &source.reply_url, &body).await;
+                entry.deny("unknown environment");
+                return;
+            }
+            Some(env) => env,
+        };
+    entry.workflow = Some(environment.workflow.clone());
+    entry.inputs = json!({ "environment": name });
+
+    let rules = environment_rules(config, environment);
+    let allowed = match rules {
+        None => false,
+        Some(rules) => policy::allowed_by(app, rules, source).await,
+    };
+    if !allowed {
+        tracing::debug!("User {} is not allowed to deploy to {name}.", source.user);
+        entry.deny(format!("not allowed to deploy to {name}"));
+        return;
+    }
+
+    if let Err(lock) = locks::check(pool, source.repo_name(), name, &source.user).await {
+        let body = format!(
+            "@{} deploy to `{name}` was not started. {}.",
+            source.user,
+            lock.describe()
+        );
+        api::comment(app, &source.reply_url, &body).await;
+        entry.deny(format!("{name} is locked by {}", lock.owner));
+        return;
+    }
+
+    if let Err(missing) = policy::reviews_satisfied(app, rules.unwrap(), pull).await {
+        let body = format!(
+            "@{} deploy to `{name}` was not started. {missing}",
+            source.user
+        );
+        api::comment(app, &source.reply_url, &body).await;
+        entry.deny(missing);
+        return;
+    }
+
+    // Deploy exactly what was reviewed, checks are left to the workflow
+    let repo_url = source.repo["url"].as_str().unwrap();
+    let head_sha = pull["head"]["sha"].as_str().unwrap();
+    let json = json!({
+        "ref": head_sha,
+        "environment": name,
+        "auto_merge": false,
+        "required_contexts": [],
+        "description": format!("#{} by @{}", source.number, source.user),
+        "payload": { "pull_request": source.number },
+    });
+    let deployment_id = match api::post_json(app, &format!("{repo_url}/deployments"), &json).await {
+        Some(deployment) if deployment["id"].is_u64() => deployment["id"].as_u64().unwrap(),
+        _ => {
+            entry.allow("could not create deployment");
+            return;
+        }
+    };
+    tracing::info!("Created deployment {deployment_id} to {name} for {head_sha}");
+
+    let body = render(name, &source.user, "pending", None, None);
+    if let Some(comment) = api::comment(app, &source.comments_url, &body).await {
+        let tracked = TrackedDeploy {
+            environment: name.clone(),
+            comment_url: comment["url"].as_str().unwrap().to_string(),
+            user: source.user.clone(),
+        };
+        let mut conn = pool.as_ref().unwrap().get().await.unwrap();
+        let _: String = cmd("SET")
+            .arg(format!("deployment.{deployment_id}"))
+            .arg(serde_json::to_string(&tracked).unwrap())
+            .arg("EX")
+            .arg(DEPLOY_EXPIRE)
+            .query_async(&mut *conn)
+            .await
+            .unwrap();
+    }
+
+    let mut inputs = environment.inputs.clone();
+    inputs.insert("environment".to_string(), Value::String(name.clone()));
+    inputs.insert(
+        "deployment_id".to_string(),
+        Value::String(deployment_id.to_string()),
+    );
+    if let Some(name) = &config.dispatch.sha_input {
+        inputs.insert(name.clone(), Value::String(head_sha.to_string()));
+    }
+    entry.inputs = Value::Object(inputs.clone());
+
+    // Run the default branch's workflow with the environment's secrets, it checks out the reviewed head
+    let branch = source.repo["default_branch"].as_str().unwrap();
+    if dispatch::run(app, repo_url, &environment.workflow, branch, inputs).await {
+        if let Some(reactions_url) = &source.reactions_url {
+            api::react(app, reactions_url, "rocket").await;
+        }
+        entry.allow(format!("deployment {deployment_id} dispatched"));
+    }
+    else {
+        let url = format!("{repo_url}/deployments/{deployment_id}/statuses");
+        let json = json!({ "state": "error", "description": "Could not dispatch the workflow" });
+        api::post_json(app, &url, &json).await;
+        entry.allow(format!("deployment {deployment_id} dispatch failed"));
+    }
+}
+
+/// Who may deploy to an environment, None if its workflow is denied.
+pub fn environment_rules<'a>(
+    config: &'a RepoConfig,
+    environment: &'a Environment,
+) -> Option<&'a WorkflowPolicy> {
+    match environment.permissions.as_ref() {
+        Some(rules) => Some(rules),
+        None => config
+            .permissions
+            .for_workflow(config::workflow_name(&environment.workflow)),
+    }
+}
+
+/// Update the comment of a tracked deployment from a `deployment_status` webhook.
+pub async fn deployment_status(app: &AppState, pool: &ConnectionPool, payload: &Value) {
+    let deployment_id = payload["deployment"]["id"].as_u64().unwrap();
+    let key = format!("deployment.{deployment_id}");
+
+    let tracked: Option<String> = {
+        let mut conn = pool.as_ref().unwrap().get().await.unwrap();
+        cmd("GET").arg(&key).query_async(&mut *conn).await.unwrap()
+    };
+    let tracked: TrackedDeploy = match tracked {
+        None => return,
+        Some(tracked) => serde_json::from_str(&tracked).unwrap(),
+    };
+
+    let deployment_status = &payload["deployment_status"];
+    let state = deployment_status["state"].as_str().unwrap();
+    let environment_url = deployment_status["environment_url"]
+        .as_str()
+        .filter(|u| !u.is_empty());
+    let log_url = deployment_status["log_url"]
+        .as_str()
+        .or(deployment_status["target_url"].as_str())
+        .filter(|u| !u.is_empty());
+
+    let body = render(
+        &tracked.environment,
+        &tracked.user,
+        state,
+        environment_url,
+        log_url,
+    );
+    let json = json!({ "body": body });
+    if api::patch_json(app, &tracked.comment_url, &json)
+        .await
+        .is_some()
+    {
+        tracing::info!(
+            "Deployment {deployment_id} to {} is {state}",
+            tracked.environment
+        );
+    }
+
+    if matches!(state, "success" | "failure" | "error" | "inactive") {
+        let mut conn = pool.as_ref().unwrap().get().await.unwrap();
+        let _: usize = cmd("DEL").arg(&key).query_async(&mut *conn).await.unwrap();
+    }
+}
+
+fn render(
+    environment: &str,
+    user: &str,
+    state: &str,
+    environment_url: Option<&str>,
+    log_url: Option<&str>,
+) -> String {
+    let mut body = format!(
+        "### Deployment to `{environment}`\n\n{} **{}**, started by @{user}",
+        status::symbol(state),
+        state.replace('_', " ")
+    );
+    if let Some(url) = environment_url {
+        body.push_str(&format!("\n\nEnvironment: {url}"));
+    }
+    if let Some(url) = log_url {
+        body.push_str(&format!("\nLogs: {url}"));
+    }
+    body
+}
@@ -0,0 +1,137 @@
+use crate::{
+    api,
+    config::{Dispatch, RefMode},
+    states::AppState,
+};
+use serde_json::{Map, Value};
+
+/// Dispatch a workflow for a pull request, `pull` being the json from the pulls api.
+/// It runs from the default branch, so a pull request cannot change the workflow that gets the repository's secrets,
+/// and is told what to check out through `ref_input` and `sha_input`.
+pub async fn pull_request(
+    app: &AppState,
+    settings: &Dispatch,
+    pull: &Value,
+    workflow: &str,
+    mut inputs: Map<String, Value>,
+) -> bool {
+    let repo = &pull["base"]["repo"];
+    let repo_url = repo["url"].as_str().unwrap();
+    let (target, sha) = match pull_target(settings.mode, pull) {
+        None => {
+            tracing::info!(
+                "No merge ref for pull request {}, not dispatching {workflow}",
+                pull["number"]
+            );
+            return false;
+        }
+        Some(target) => target,
+    };
+    if let Some(name) = &settings.ref_input {
+        inputs.insert(name.clone(), Value::String(target));
+    }
+    if let Some(name) = &settings.sha_input {
+        inputs.insert(name.clone(), Value::String(sha));
+    }
+
+    let branch = repo["default_branch"].as_str().unwrap();
+    run(app, repo_url, workflow, branch, inputs).await
+}
+
+/// Dispatch a workflow in a repository on a ref.
+pub async fn run(
+    app: &AppState,
+    repo_url: &str,
+    workflow: &str,
+    git_ref: &str,
+    inputs: Map<String, Value>,
+) -> bool {
+    let url = format!("{repo_url}/actions/workflows/{workflow}/dispatches");
+
+    let mut json_map = Map::new();
+    json_map.insert("ref".to_string(), Value::String(git_ref.to_string()));
+    if !inputs.is_empty() {
+        json_map.insert("inputs".to_string(), Value::Object(inputs));
+    }
+    let json = Value::Object(json_map);
+
+    match api::post_json(app, &url, &json).await {
+        Some(_) => {
+            tracing::info!("Started workflow {workflow} with {json}");
+            true
+        }
+        None => false,
+    }
+}
+
+/// The ref a workflow should check out for a pull request and the commit sha it points to.
+/// None if the merge ref was asked for and GitHub has not made one.
+pub fn pull_target(mode: RefMode, pull: &Value) -> Option<(String, String)> {
+    let number = pull["number"].as_u64().unwrap();
+    let head_sha = pull["head"]["sha"].as_str().unwrap().to_string();
+
+    // A fork's branch does not exist in the base repository, and a deleted fork has no repo
+    let fork =
+        pull["head"]["repo"]["full_name"].as_str() != pull["base"]["repo"]["full_name"].as_str();
+
+    match mode {
+        RefMode::Branch if !fork => {
+            let branch = pull["head"]["ref"].as_str().unwrap().to_string();
+            Some((branch, head_sha))
+        }
+        RefMode::Branch | RefMode::Head => Some((format!("refs/pull/{number}/head"), head_sha)),
+        RefMode::Merge => {
+            let merge_sha = pull["merge_commit_sha"].as_str()?;
+            if pull["mergeable"].as_bool().eq(&Some(false)) {
+                return None;
+            }
+            Some((format!("refs/pull/{number}/merge"), merge_sha.to_string()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn pull(head_repo: &str, mergeable: Option<bool>) -> Value {
+        json!({
+            "number": 7,
+            "head": { "sha": "aaa", "ref": "feature", "repo": { "full_name": head_repo } },
+            "base": { "repo": { "full_name": "owner/repo" } },
+            "merge_commit_sha": "mmm",
+            "mergeable": mergeable,
+        })
+    }
+
+    #[test]
+    fn branch_falls_back_to_head_ref_for_forks() {
+        let same = pull("owner/repo", None);
+        assert_eq!(
+            pull_target(RefMode::Branch, &same),
+            Some(("feature".to_string(), "aaa".to_string()))
+        );
+        let fork = pull("someone/repo", None);
+        assert_eq!(
+            pull_target(RefMode::Branch, &fork),
+            Some(("refs/pull/7/head".to_string(), "aaa".to_string()))
+        );
+        assert_eq!(
+            pull_target(RefMode::Head, &same),
+            Some(("refs/pull/7/head".to_string(), "aaa".to_string()))
+        );
+    }
+
+    #[test]
+    fn merge_needs_a_mergeable_pull_request() {
+        assert_eq!(
+            pull_target(RefMode::Merge, &pull("owner/repo", Some(true))),
+            Some(("refs/pull/7/merge".to_string(), "mmm".to_string()))
+        );
+        assert_eq!(
+            pull_target(RefMode::Merge, &pull("owner/repo", Some(false))),
+            None
+        );
+    }
+}
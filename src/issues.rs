@@ -0,0 +1,159 @@
+use crate::{api, audit, commands::Command, config::RepoConfig, sources::Source, states::AppState};
+use serde_json::{json, Value};
+
+/// Reasons GitHub accepts for locking a conversation.
+static LOCK_REASONS: [&str; 4] = ["off-topic", "too heated", "resolved", "spam"];
+
+/// `close [--not-planned]`, `reopen`, `lock [reason]`, `duplicate-of #<number>` and `transfer <owner/repo>`.
+/// Each command has its own entry of `permissions.commands`.
+pub async fn command(
+    app: &AppState,
+    config: &RepoConfig,
+    source: &Source,
+    command: &Command<'_>,
+    entry: &mut audit::Entry,
+) {
+    let login = source.user.as_str();
+
+    let args = command.args.unwrap_or_default().trim();
+    entry.inputs = if args.is_empty() { Value::Null } else { Value::String(args.to_string()) };
+
+    let repo_url = source.repo["url"].as_str().unwrap();
+    let issue_url = format!("{repo_url}/issues/{}", source.number);
+    let done = match command.name {
+        "close" => {
+            let reason = if command.flag("not-planned") { "not_planned" } else { "completed" };
+            let json = json!({ "state": "closed", "state_reason": reason });
+            api::patch_json(app, &issue_url, &json).await.is_some()
+        }
+        "reopen" => {
+            let json = json!({ "state": "open" });
+            api::patch_json(app, &issue_url, &json).await.is_some()
+        }
+        "lock" => {
+            let reason = match args {
+                "" => None,
+                reason => match LOCK_REASONS.iter().find(|r| r.eq_ignore_ascii_case(reason)) {
+                    None => {
+                        let body = format!(
+                            "@{login} lock reasons are {}.",
+                            LOCK_REASONS.map(|r| format!("`{r}`")).join(", ")
+                        );
+                        api::comment(app, &source.reply_url, &body).await;
+                        entry.deny("unknown lock reason");
+                        return;
+                    }
+                    reason => reason,
+                },
+            };
+            let json = json!({ "lock_reason": reason });
+            api::put_json(app, &format!("{issue_url}/lock"), &json)
+                .await
+                .is_some()
+        }
+        "duplicate-of" => {
+            let original = args
+                .trim_start_matches('#')
+                .parse::<u64>()
+                .ok()
+                .filter(|n| !n.eq(&source.number));
+            let exists = match original {
+                None => false,
+                Some(n) => api::get_json(app, &format!("{repo_url}/issues/{n}"))
+                    .await
+                    .is_some(),
+            };
+            if !exists {
+                let body = format!("Usage: `{} duplicate-of #<number>`", config.prefix);
+                api::comment(app, &source.reply_url, &body).await;
+                entry.deny("no original issue");
+                return;
+            }
+
+            // GitHub marks the issue as a duplicate from the comment
+            let body = format!("Duplicate of #{}", original.unwrap());
+            let json = json!({ "state": "closed", "state_reason": "not_planned" });
+            api::comment(app, &source.comments_url, &body)
+                .await
+                .is_some()
+                && api::patch_json(app, &issue_url, &json).await.is_some()
+        }
+        _ => {
+            if source.pull_url.is_some() {
+                let body = format!("@{login} pull requests cannot be transferred.");
+                api::comment(app, &source.reply_url, &body).await;
+                entry.deny("not an issue");
+                return;
+            }
+            let target = config
+                .triage
+                .transfer_to
+                .iter()
+                .find(|r| r.eq_ignore_ascii_case(args));
+            match target {
+                None => {
+                    let allowed = config
+                        .triage
+                        .transfer_to
+                        .iter()
+                        .map(|r| format!("`{r}`"))
+                        .collect::<Vec<_>>();
+                    let body = format!(
+                        "@{login} issues can be transferred to {}.",
+                        if allowed.is_empty() {
+                            "no repository".to_string()
+                        }
+                        else {
+                            allowed.join(", ")
+                        }
+                    );
+                    api::comment(app, &source.reply_url, &body).await;
+                    entry.deny("repository not allowed");
+                    return;
+                }
+                Some(target) => transfer(app, &issue_url, target).await,
+            }
+        }
+    };
+
+    if done {
+        if let Some(reactions_url) = &source.reactions_url {
+            api::react(app, reactions_url, "+1").await;
+        }
+        entry.allow(format!("{} done", command.name));
+    }
+    else {
+        entry.allow(format!("{} failed", command.name));
+    }
+}
+
+/// Move an issue to another repository, which the REST api cannot do.
+async fn transfer(app: &AppState, issue_url: &str, target: &str) -> bool {
+    let issue_id = match api::get_json(app, issue_url).await {
+        None => return false,
+        Some(issue) => issue["node_id"].clone(),
+    };
+    let repo_id = match api::get_json(app, &format!("https://api.github.com/repos/{target}")).await
+    {
+        None => return false,
+        Some(repo) => repo["node_id"].clone(),
+    };
+
+    let json = json!({
+        "query": "mutation($issue: ID!, $repo: ID!) { \
+            transferIssue(input: { issueId: $issue, repositoryId: $repo }) { issue { url } } }",
+        "variables": { "issue": issue_id, "repo": repo_id },
+    });
+    match api::post_json(app, "https://api.github.com/graphql", &json).await {
+        // GraphQL answers errors with a success status
+        Some(res) if res["errors"].is_null() => {
+            tracing::info!("Transferred {issue_url} to {target}");
+            true
+        }
+        Some(res) => {
+            tracing::error!("Could not transfer {issue_url}: {}", res["errors"]);
+            false
+        }
+        None => false,
+    }
+}
@@ -0,0 +1,263 @@
+use crate::{
+    api, audit,
+    commands::Command,
+    config::RepoConfig,
+    deploy, policy,
+    sources::Source,
+    states::{AppState, ConnectionPool},
+};
+use bb8_redis::redis::cmd;
+use chrono::{Duration, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+/// A lock on a deploy environment, only its owner can deploy while it is held.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Lock {
+    pub environment: String,
+    pub owner: String,
+    /// Pull request the lock was taken on.
+    pub number: u64,
+    /// Unix time in seconds.
+    pub expires: i64,
+}
+impl Lock {
+    pub fn describe(&self) -> String {
+        let expires = Utc.timestamp_opt(self.expires, 0).unwrap();
+        format!(
+            "`{}` is locked by @{} from #{} until {} UTC",
+            self.environment,
+            self.owner,
+            self.number,
+            expires.format("%Y-%m-%d %H:%M")
+        )
+    }
+}
+
+/// Hash of a repository's locks, keyed by environment.
+fn lock_key(repo: &str) -> String {
+    format!("lock.{repo}")
+}
+
+/// Take a lock unless someone else holds it and it has not expired, returning the lock that is held.
+/// The hash lives as long as its longest lock, expired locks are left until they are taken over.
+static LOCK_SCRIPT: &str = r#"
+local held = redis.call('HGET', KEYS[1], ARGV[1])
+if held then
+    local lock = cjson.decode(held)
+    if lock.expires > tonumber(ARGV[4]) and string.lower(lock.owner) ~= string.lower(ARGV[2]) then
+        return held
+    end
+end
+redis.call('HSET', KEYS[1], ARGV[1], ARGV[3])
+local ttl = redis.call('TTL', KEYS[1])
+local left = tonumber(ARGV[5]) - tonumber(ARGV[4])
+if ttl < left then
+    redis.call('EXPIRE', KEYS[1], left)
+end
+return false
+"#;
+
+/// Remove a lock only if it is still the one that was read.
+static UNLOCK_SCRIPT: &str = r#"
+if redis.call('HGET', KEYS[1], ARGV[1]) == ARGV[2] then
+    return redis.call('HDEL', KEYS[1], ARGV[1])
+end
+return 0
+"#;
+
+/// Whether a command's first argument is a deploy environment, a `lock` without one locks the conversation.
+pub fn names_environment(config: &RepoConfig, command: &Command<'_>) -> bool {
+    command
+        .args
+        .and_then(|a| a.split_whitespace().next())
+        .is_some_and(|e| config.deploy.environments.contains_key(e))
+}
+
+/// Fails with the lock if an environment is locked by someone else.
+pub async fn check(
+    pool: &ConnectionPool,
+    repo: &str,
+    environment: &str,
+    user: &str,
+) -> Result<(), Lock> {
+    match get(pool, repo, environment).await {
+        Some((lock, _)) if !lock.owner.eq_ignore_ascii_case(user) => Err(lock),
+        _ => Ok(()),
+    }
+}
+
+/// The lock on an environment and how it is stored, None if there is none or it expired.
+async fn get(pool: &ConnectionPool, repo: &str, environment: &str) -> Option<(Lock, String)> {
+    let mut conn = pool.as_ref().unwrap().get().await.unwrap();
+    let stored: Option<String> = cmd("HGET")
+        .arg(lock_key(repo))
+        .arg(environment)
+        .query_async(&mut *conn)
+        .await
+        .unwrap();
+    let stored = stored?;
+    serde_json::from_str::<Lock>(&stored)
+        .ok()
+        .filter(|l| l.expires > Utc::now().timestamp())
+        .map(|l| (l, stored))
+}
+
+/// `lock <environment> [hours]`, `unlock [--force] <environment>` and `locks`.
+pub async fn command(
+    app: &AppState,
+    pool: &ConnectionPool,
+    config: &RepoConfig,
+    source: &Source,
+    command: &Command<'_>,
+    entry: &mut audit::Entry,
+) {
+    let repo = source.repo_name();
+    if command.name.eq("locks") {
+        let body = list(pool, repo).await;
+        api::comment(app, &source.reply_url, &body).await;
+        entry.allow("listed locks");
+        return;
+    }
+
+    let mut args = command.args.unwrap_or_default().split_whitespace();
+    let (name, environment) = match args
+        .next()
+        .and_then(|e| config.deploy.environments.get_key_value(e))
+    {
+        None => {
+            let body = format!(
+                "Usage: `{} {} <environment>`, with an environment set up for `deploy`.",
+                config.prefix, command.name
+            );
+            api::comment(app, &source.reply_url, &body).await;
+            entry.deny("unknown environment");
+            return;
+        }
+        Some(env) => env,
+    };
+    entry.inputs = json!({ "environment": name });
+
+    // Locking is for people who could deploy
+    let allowed = match deploy::environment_rules(config, environment) {
+        None => false,
+        Some(rules) => policy::allowed_by(app, rules, source).await,
+    };
+    if !allowed {
+        entry.deny(format!("not allowed to deploy to {name}"));
+        return;
+    }
+
+    let key = lock_key(repo);
+    if command.name.eq("lock") {
+        let hours = args
+            .next()
+            .and_then(|h| h.parse().ok())
+            .unwrap_or(config.deploy.lock_hours)
+            .min(config.deploy.max_lock_hours)
+            .max(1);
+        let now = Utc::now();
+        let lock = Lock {
+            environment: name.clone(),
+            owner: source.user.clone(),
+            number: source.number,
+            expires: (now + Duration::hours(hours.into())).timestamp(),
+        };
+        let held: Option<String> = {
+            let mut conn = pool.as_ref().unwrap().get().await.unwrap();
+            cmd("EVAL")
+                .arg(LOCK_SCRIPT)
+                .arg(1)
+                .arg(&key)
+                .arg(name)
+                .arg(&source.user)
+                .arg(serde_json::to_string(&lock).unwrap())
+                .arg(now.timestamp())
+                .arg(lock.expires)
+                .query_async(&mut *conn)
+                .await
+                .unwrap()
+        };
+        if let Some(held) = held.and_then(|h| serde_json::from_str::<Lock>(&h).ok()) {
+            api::comment(app, &source.reply_url, &format!("{}.", held.describe())).await;
+            entry.deny(format!("{name} is locked by {}", held.owner));
+            return;
+        }
+        tracing::info!("Locked {name} in {repo} for {}", source.user);
+
+        api::comment(app, &source.reply_url, &format!("{}.", lock.describe())).await;
+        entry.allow("locked");
+    }
+    else {
+        match get(pool, repo, name).await {
+            None => {
+                let body = format!("`{name}` is not locked.");
+                api::comment(app, &source.reply_url, &body).await;
+                entry.allow("not locked");
+            }
+            Some((lock, _))
+                if !lock.owner.eq_ignore_ascii_case(&source.user) && !command.flag("force") =>
+            {
+                let body = format!(
+                    "{}. Comment `{} unlock --force {name}` to take it off anyway.",
+                    lock.describe(),
+                    config.prefix
+                );
+                api::comment(app, &source.reply_url, &body).await;
+                entry.deny(format!("{name} is locked by {}", lock.owner));
+            }
+            Some((lock, stored)) => {
+                let removed: usize = {
+                    let mut conn = pool.as_ref().unwrap().get().await.unwrap();
+                    cmd("EVAL")
+                        .arg(UNLOCK_SCRIPT)
+                        .arg(1)
+                        .arg(&key)
+                        .arg(name)
+                        .arg(&stored)
+                        .query_async(&mut *conn)
+                        .await
+                        .unwrap()
+                };
+                if removed == 0 {
+                    let body = format!("`{name}` changed hands while unlocking, try again.");
+                    api::comment(app, &source.reply_url, &body).await;
+                    entry.allow("lock changed");
+                    return;
+                }
+                tracing::info!("Unlocked {name} in {repo}, held by {}", lock.owner);
+
+                let body = format!("`{name}` is unlocked, it was held by @{}.", lock.owner);
+                api::comment(app, &source.reply_url, &body).await;
+                entry.allow("unlocked");
+            }
+        }
+    }
+}
+
+async fn list(pool: &ConnectionPool, repo: &str) -> String {
+    let locks: Vec<String> = {
+        let mut conn = pool.as_ref().unwrap().get().await.unwrap();
+        cmd("HVALS")
+            .arg(lock_key(repo))
+            .query_async(&mut *conn)
+            .await
+            .unwrap()
+    };
+    let now = Utc::now().timestamp();
+    let mut locks: Vec<Lock> = locks
+        .iter()
+        .filter_map(|l| serde_json::from_str(l).ok())
+        .filter(|l: &Lock| l.expires > now)
+        .collect();
+    if locks.is_empty() {
+        return "No environments are locked.".to_string();
+    }
+    locks.sort_by(|a, b| a.environment.cmp(&b.environment));
+
+    locks
+        .iter()
+        .map(|l| format!("- {}", l.describe()))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
@@ -1,6 +1,32 @@
+mod admin;
+mod api;
+mod audit;
+mod autolabel;
+mod backport;
+mod changelog;
+mod checks;
+mod commands;
+mod config;
+mod conventional;
+mod dco;
+mod deploy;
+mod dispatch;
 mod fetch;
+mod issues;
+mod locks;
+mod merge;
+mod policy;
+mod queue;
+mod ratelimit;
+mod release;
+mod runs;
 mod secure;
+mod size;
+mod sources;
 mod states;
+mod status;
+mod triage;
+mod update;
 mod webhooks;
 
 use axum::{
@@ -10,6 +36,7 @@ use axum::{
     Router,
 };
 use mimalloc::MiMalloc;
+use once_cell::sync::Lazy;
 use std::net::SocketAddr;
 use tower_http::trace::TraceLayer;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
@@ -30,10 +57,15 @@ async fn main() {
         .with(tracing_subscriber::fmt::layer())
         .init();
 
+    // A bad COMMAND_POLICY should stop the server, not every command
+    Lazy::force(&policy::POLICY);
+
+    let app_state = states::build_app_state().await;
     let app = Router::new()
         .route("/", get(redirect_home))
         .route("/health/ready", get(health_ready))
-        .nest("/webhooks", webhooks::get_routes().await)
+        .nest("/webhooks", webhooks::get_routes(app_state.clone()))
+        .nest("/admin", admin::get_routes(app_state))
         .fallback(error404)
         .with_state(states::AppState::new_empty())
         .layer(TraceLayer::new_for_http());
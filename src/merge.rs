@@ -0,0 +1,337 @@
+use crate::{
+    api, audit,
+    commands::Command,
+    config::{MergeMethod, RepoConfig},
+    policy,
+    sources::Source,
+    states::{AppState, ConnectionPool},
+};
+use bb8_redis::redis::cmd;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::time::Duration;
+
+static AUTOMERGE_EXPIRE: usize = 604800;
+
+/// Policy entry used for both commands.
+pub static MERGE_POLICY: &str = "merge";
+
+/// An `automerge` waiting for the pull request's checks.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Intent {
+    user: String,
+    method: MergeMethod,
+    /// Head commit when the command was given, new commits cancel the intent.
+    sha: String,
+    comments_url: String,
+}
+
+fn intent_key(repo: &str, number: u64) -> String {
+    format!("automerge.{repo}.{number}")
+}
+
+/// Whether a pull request can be merged right now.
+pub enum Readiness {
+    Ready,
+    /// Might become ready without anyone doing anything.
+    Waiting(String),
+    Blocked(String),
+}
+
+/// `merge [squash|rebase|merge]` and `automerge [squash|rebase|merge]`, `automerge --cancel` drops the intent.
+pub async fn command(
+    app: &AppState,
+    pool: &ConnectionPool,
+    config: &RepoConfig,
+    source: &Source,
+    command: &Command<'_>,
+    entry: &mut audit::Entry,
+) {
+    let login = source.user.as_str();
+    let method = match method(config, command) {
+        None => {
+            let body = format!(
+                "Usage: `{} {} [squash|rebase|merge]`",
+                config.prefix, command.name
+            );
+            api::comment(app, &source.reply_url, &body).await;
+            entry.deny("unknown merge method");
+            return;
+        }
+        Some(method) => method,
+    };
+    entry.inputs = json!({ "method": method });
+
+    let key = intent_key(source.repo_name(), source.number);
+    if command.name.eq("automerge") && command.flag("cancel") {
+        let mut conn = pool.as_ref().unwrap().get().await.unwrap();
+        let removed: usize = cmd("DEL").arg(&key).query_async(&mut *conn).await.unwrap();
+        let body = match removed {
+            0 => "Automerge was not on for this pull request.".to_string(),
+            _ => "Automerge is cancelled.".to_string(),
+        };
+        api::comment(app, &source.reply_url, &body).await;
+        entry.allow("automerge cancelled");
+        return;
+    }
+
+    let pull_url = source.pull_url.as_deref().unwrap();
+    let repo_url = source.repo["url"].as_str().unwrap();
+    let (pull, readiness) = match pull_readiness(app, repo_url, pull_url).await {
+        None => {
+            entry.allow("could not get pull request");
+            return;
+        }
+        Some(ready) => ready,
+    };
+
+    let rules = config.permissions.for_command(MERGE_POLICY);
+    if let Err(missing) = policy::reviews_satisfied(app, rules, &pull).await {
+        let body = format!("@{login} the pull request was not merged. {missing}");
+        api::comment(app, &source.reply_url, &body).await;
+        entry.deny(missing);
+        return;
+    }
+    let sha = pull["head"]["sha"].as_str().unwrap();
+
+    match (command.name, readiness) {
+        (_, Readiness::Ready) => {
+            if merge(app, pull_url, sha, method).await {
+                if let Some(reactions_url) = &source.reactions_url {
+                    api::react(app, reactions_url, "rocket").await;
+                }
+                entry.allow("merged");
+            }
+            else {
+                let body = format!("@{login} GitHub would not merge the pull request.");
+                api::comment(app, &source.reply_url, &body).await;
+                entry.allow("merge failed");
+            }
+        }
+        ("automerge", Readiness::Waiting(reason)) => {
+            let intent = Intent {
+                user: login.to_string(),
+                method,
+                sha: sha.to_string(),
+                comments_url: source.comments_url.clone(),
+            };
+            let mut conn = pool.as_ref().unwrap().get().await.unwrap();
+            let _: String = cmd("SET")
+                .arg(&key)
+                .arg(serde_json::to_string(&intent).unwrap())
+                .arg("EX")
+                .arg(AUTOMERGE_EXPIRE)
+                .query_async(&mut *conn)
+                .await
+                .unwrap();
+            tracing::info!("Automerge on for {} #{}", source.repo_name(), source.number);
+
+            let body = format!(
+                "Automerge is on, the pull request will be merged once it can be: {reason}."
+            );
+            api::comment(app, &source.reply_url, &body).await;
+            entry.allow("automerge on");
+        }
+        (_, Readiness::Waiting(reason) | Readiness::Blocked(reason)) => {
+            let body = format!("@{login} the pull request cannot be merged, {reason}.");
+            api::comment(app, &source.reply_url, &body).await;
+            entry.deny(reason);
+        }
+    }
+}
+
+/// The merge method a command names, the configured one when it names none.
+pub fn method(config: &RepoConfig, command: &Command<'_>) -> Option<MergeMethod> {
+    match command.args.and_then(|a| a.split_whitespace().next()) {
+        None => Some(config.merge.method),
+        Some(method) => serde_json::from_value(Value::String(method.to_string())).ok(),
+    }
+}
+
+/// Merge pull requests with automerge on when a `check_suite` or `check_run` completes for them.
+pub async fn check_completed(app: &AppState, pool: &ConnectionPool, event: &str, payload: &Value) {
+    if !payload["action"].as_str().unwrap().eq("completed") {
+        return;
+    }
+
+    let repo = &payload["repository"];
+    let full_name = repo["full_name"].as_str().unwrap();
+    let repo_url = repo["url"].as_str().unwrap();
+    // Empty for pull requests from forks
+    let numbers: Vec<u64> = payload[event]["pull_requests"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .filter_map(|p| p["number"].as_u64())
+        .collect();
+
+    for number in numbers {
+        let key = intent_key(full_name, number);
+        let intent: Option<String> = {
+            let mut conn = pool.as_ref().unwrap().get().await.unwrap();
+            cmd("GET").arg(&key).query_async(&mut *conn).await.unwrap()
+        };
+        let intent: Intent = match intent {
+            None => continue,
+            Some(intent) => serde_json::from_str(&intent).unwrap(),
+        };
+
+        let pull_url = format!("{repo_url}/pulls/{number}");
+        let (pull, readiness) = match pull_readiness(app, repo_url, &pull_url).await {
+            None => continue,
+            Some(ready) => ready,
+        };
+
+        let readiness = match pull["head"]["sha"].as_str() {
+            Some(sha) if !sha.eq(&intent.sha) => {
+                Readiness::Blocked("new commits were pushed".to_string())
+            }
+            _ => readiness,
+        };
+        let blocked = match readiness {
+            Readiness::Waiting(_) => continue,
+            Readiness::Blocked(reason) => Some(reason),
+            Readiness::Ready => None,
+        };
+
+        // Only one completed check gets to act on the intent
+        let taken: Option<String> = {
+            let mut conn = pool.as_ref().unwrap().get().await.unwrap();
+            cmd("GETDEL")
+                .arg(&key)
+                .query_async(&mut *conn)
+                .await
+                .unwrap()
+        };
+        if taken.is_none() {
+            continue;
+        }
+
+        let reason = match blocked {
+            Some(reason) => reason,
+            None if merge(app, &pull_url, &intent.sha, intent.method).await => {
+                tracing::info!("Automerged {full_name} #{number}");
+                continue;
+            }
+            None => "GitHub would not merge the pull request".to_string(),
+        };
+        let body = format!("@{} automerge is cancelled, {reason}.", intent.user);
+        api::comment(app, &intent.comments_url, &body).await;
+    }
+}
+
+/// Merge at a head commit, so nothing pushed since the checks ran gets merged.
+pub async fn merge(app: &AppState, pull_url: &str, sha: &str, method: MergeMethod) -> bool {
+    let json = json!({ "sha": sha, "merge_method": method });
+    api::put_json(app, &format!("{pull_url}/merge"), &json)
+        .await
+        .is_some_and(|res| res["merged"].as_bool().unwrap_or(false))
+}
+
+/// Get a pull request and whether it can be merged.
+pub async fn pull_readiness(
+    app: &AppState,
+    repo_url: &str,
+    pull_url: &str,
+) -> Option<(Value, Readiness)> {
+    // GitHub works out mergeability in the background after a change, give it a moment
+    let mut pull = Value::Null;
+    for attempt in 0..3 {
+        if attempt > 0 {
+            tokio::time::sleep(Duration::from_secs(2)).await;
+        }
+        pull = api::get_json(app, pull_url).await?;
+        if !pull["mergeable"].is_null() {
+            break;
+        }
+    }
+
+    let readiness = if !pull["state"].as_str().unwrap().eq("open") {
+        Readiness::Blocked("it is closed".to_string())
+    }
+    else if pull["draft"].as_bool().unwrap_or(false) {
+        Readiness::Blocked("it is a draft".to_string())
+    }
+    else if pull["mergeable"].as_bool().eq(&Some(false)) {
+        Readiness::Blocked("it has conflicts with the base branch".to_string())
+    }
+    else {
+        let sha = pull["head"]["sha"].as_str().unwrap();
+        match checks(app, repo_url, sha).await {
+            Readiness::Ready => match pull["mergeable_state"].as_str().unwrap_or("unknown") {
+                "clean" | "has_hooks" | "unstable" => Readiness::Ready,
+                "behind" => Readiness::Blocked("it is behind the base branch".to_string()),
+                "blocked" => Readiness::Waiting("branch protection rules are not met".to_string()),
+                _ => Readiness::Waiting("GitHub is still checking if it can be merged".to_string()),
+            },
+            checks => checks,
+        }
+    };
+    Some((pull, readiness))
+}
+
+/// Combined state of the check runs and commit statuses of a commit.
+async fn checks(app: &AppState, repo_url: &str, sha: &str) -> Readiness {
+    let mut pending = Vec::new();
+    let mut failed = Vec::new();
+
+    let url = format!("{repo_url}/commits/{sha}/check-runs");
+    let check_runs = match all_pages(app, &url, "check_runs").await {
+        None => return Readiness::Waiting("the checks could not be read".to_string()),
+        Some(check_runs) => check_runs,
+    };
+    for run in &check_runs {
+        let name = run["name"].as_str().unwrap_or_default().to_string();
+        match (run["status"].as_str(), run["conclusion"].as_str()) {
+            (Some("completed"), Some("success" | "neutral" | "skipped")) => {}
+            (Some("completed"), _) => failed.push(name),
+            _ => pending.push(name),
+        }
+    }
+
+    let url = format!("{repo_url}/commits/{sha}/status");
+    let statuses = match all_pages(app, &url, "statuses").await {
+        None => return Readiness::Waiting("the commit statuses could not be read".to_string()),
+        Some(statuses) => statuses,
+    };
+    for status in &statuses {
+        let name = status["context"].as_str().unwrap_or_default().to_string();
+        match status["state"].as_str() {
+            Some("success") => {}
+            Some("pending") => pending.push(name),
+            _ => failed.push(name),
+        }
+    }
+
+    let list = |names: Vec<String>| {
+        names
+            .iter()
+            .map(|n| format!("`{n}`"))
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    if !failed.is_empty() {
+        Readiness::Blocked(format!("checks failed: {}", list(failed)))
+    }
+    else if !pending.is_empty() {
+        Readiness::Waiting(format!("checks are running: {}", list(pending)))
+    }
+    else {
+        Readiness::Ready
+    }
+}
+
+/// Every page of an api response that holds its list in a field next to `total_count`, like check runs.
+async fn all_pages(app: &AppState, url: &str, field: &str) -> Option<Vec<Value>> {
+    let mut items = Vec::new();
+    for page in 1.. {
+        let json = api::get_json(app, &format!("{url}?per_page=100&page={page}")).await?;
+        let list = json[field].as_array()?;
+        items.extend(list.iter().cloned());
+        let total = json["total_count"].as_u64().unwrap_or(0) as usize;
+        if list.len() < 100 || items.len() >= total {
+            break;
+        }
+    }
+    Some(items)
+}
@@ -0,0 +1,488 @@
+use crate::{api, sources::Source, states::AppState};
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+use serde_json::Value;
+use std::{collections::HashMap, env};
+
+/// Command policy, read as json from COMMAND_POLICY.
+/// Without it every workflow is open to OWNER | MEMBER | COLLABORATOR.
+pub static POLICY: Lazy<Policy> = Lazy::new(|| match env::var("COMMAND_POLICY") {
+    Ok(json) => serde_json::from_str(&json).expect("COMMAND_POLICY is not a valid policy"),
+    Err(_) => Policy::default(),
+});
+
+/// Built in commands that change code with the app's token or let a pull request past a check.
+/// Without their own entry they need write permission, rather than the default rules.
+static WRITE_COMMANDS: [&str; 6] = [
+    "merge",
+    "automerge",
+    "backport",
+    "update",
+    "rebase",
+    "dco-override",
+];
+static WRITERS: Lazy<WorkflowPolicy> = Lazy::new(|| WorkflowPolicy {
+    permission: Some(Permission::Write),
+    ..WorkflowPolicy::default()
+});
+
+/// Repository permission levels, lowest to highest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Permission {
+    None,
+    Read,
+    Triage,
+    Write,
+    Maintain,
+    Admin,
+}
+
+/// Who may dispatch a workflow. A user passes if they match any allow rule and are not denied.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct WorkflowPolicy {
+    /// Comment author associations, like OWNER or COLLABORATOR.
+    pub associations: Vec<String>,
+    pub users: Vec<String>,
+    /// Team slugs in the repository owner's org, or "org/team-slug".
+    pub teams: Vec<String>,
+    /// Minimum permission on the repository.
+    pub permission: Option<Permission>,
+    /// Users that are never allowed, even if another rule matches.
+    pub deny: Vec<String>,
+    /// Approving reviews of its head commit a pull request needs, with no changes requested, before dispatching.
+    /// Only reviews from owners, members and collaborators count.
+    pub approvals: usize,
+}
+impl WorkflowPolicy {
+    fn maintainers() -> Self {
+        Self {
+            associations: vec![
+                "OWNER".to_string(),
+                "MEMBER".to_string(),
+                "COLLABORATOR".to_string(),
+            ],
+            ..Self::default()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Policy {
+    /// Used for any workflow without its own entry.
+    pub default: WorkflowPolicy,
+    pub workflows: HashMap<String, WorkflowPolicy>,
+    /// Built in commands by name, kept apart from workflows so a workflow file cannot share their rules.
+    pub commands: HashMap<String, WorkflowPolicy>,
+    /// Workflows that can never be dispatched from a command.
+    pub deny: Vec<String>,
+}
+impl Policy {
+    /// None if the workflow is on the deny list.
+    pub fn for_workflow(&self, workflow: &str) -> Option<&WorkflowPolicy> {
+        if self.deny.iter().any(|w| w.eq(workflow)) {
+            return None;
+        }
+        Some(self.workflows.get(workflow).unwrap_or(&self.default))
+    }
+
+    /// Rules for a built in command, its own entry or what it falls back to.
+    pub fn for_command(&self, command: &str) -> &WorkflowPolicy {
+        match self.commands.get(command) {
+            Some(rules) => rules,
+            None if WRITE_COMMANDS.contains(&command) => &WRITERS,
+            None => &self.default,
+        }
+    }
+}
+impl Policy {
+    /// A repository's policy on top of this one, entry by entry.
+    /// Denied workflows and denied users of replaced entries are kept, a repository cannot lift them.
+    pub fn layer(&self, repo: RepoPolicy) -> Policy {
+        let keep_deny = |global: Option<&WorkflowPolicy>, mut rules: WorkflowPolicy| {
+            for user in global.into_iter().flat_map(|g| g.deny.iter()) {
+                if !rules.deny.iter().any(|u| u.eq_ignore_ascii_case(user)) {
+                    rules.deny.push(user.clone());
+                }
+            }
+            rules
+        };
+
+        let mut policy = self.clone();
+        if let Some(default) = repo.default {
+            policy.default = keep_deny(Some(&self.default), default);
+        }
+        for (name, rules) in repo.workflows {
+            let rules = keep_deny(self.workflows.get(&name), rules);
+            policy.workflows.insert(name, rules);
+        }
+        for (name, rules) in repo.commands {
+            let rules = keep_deny(self.commands.get(&name), rules);
+            policy.commands.insert(name, rules);
+        }
+        for workflow in repo.deny {
+            if !policy.deny.contains(&workflow) {
+                policy.deny.push(workflow);
+            }
+        }
+        policy
+    }
+}
+impl Default for Policy {
+    fn default() -> Self {
+        Self {
+            default: WorkflowPolicy::maintainers(),
+            workflows: HashMap::new(),
+            commands: HashMap::new(),
+            deny: Vec::new(),
+        }
+    }
+}
+
+/// `permissions` from a repository's config, every part is optional and layered over `COMMAND_POLICY`.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct RepoPolicy {
+    pub default: Option<WorkflowPolicy>,
+    pub workflows: HashMap<String, WorkflowPolicy>,
+    pub commands: HashMap<String, WorkflowPolicy>,
+    pub deny: Vec<String>,
+}
+
+/// Check if the user behind a command may dispatch a workflow in its repository.
+pub async fn allowed(app: &AppState, policy: &Policy, source: &Source, workflow: &str) -> bool {
+    let rules = match policy.for_workflow(workflow) {
+        None => {
+            tracing::debug!("Workflow {workflow} is denied by policy.");
+            return false;
+        }
+        Some(rules) => rules,
+    };
+
+    if allowed_by(app, rules, source).await {
+        return true;
+    }
+    tracing::debug!(
+        "User {} ({}) is not allowed to run workflow {workflow}.",
+        source.user,
+        source.association
+    );
+    false
+}
+
+/// Check if the user behind a built in command may run it in its repository.
+pub async fn command_allowed(
+    app: &AppState,
+    policy: &Policy,
+    source: &Source,
+    command: &str,
+) -> bool {
+    if allowed_by(app, policy.for_command(command), source).await {
+        return true;
+    }
+    tracing::debug!(
+        "User {} ({}) is not allowed to run command {command}.",
+        source.user,
+        source.association
+    );
+    false
+}
+
+/// Check if the user behind a command passes a set of rules in its repository.
+pub async fn allowed_by(app: &AppState, rules: &WorkflowPolicy, source: &Source) -> bool {
+    let login = source.user.as_str();
+    if rules.deny.iter().any(|u| u.eq_ignore_ascii_case(login)) {
+        return false;
+    }
+    if rules.associations.iter().any(|a| a.eq(&source.association))
+        || rules.users.iter().any(|u| u.eq_ignore_ascii_case(login))
+    {
+        return true;
+    }
+
+    let repo_url = source.repo["url"].as_str().unwrap();
+    let owner = source.repo["owner"]["login"].as_str().unwrap();
+    for team in rules.teams.iter() {
+        let (org, slug) = team.split_once('/').unwrap_or((owner, team));
+        if team_member(app, org, slug, login).await {
+            return true;
+        }
+    }
+
+    if let Some(min) = rules.permission {
+        let permission = match source.permission {
+            Some(permission) => Some(permission),
+            None => repo_permission(app, repo_url, login).await,
+        };
+        if let Some(permission) = permission {
+            return permission >= min;
+        }
+    }
+
+    false
+}
+
+/// The association GitHub would give a user's comment, for events that have none.
+pub async fn association(
+    app: &AppState,
+    repo: &Value,
+    login: &str,
+    permission: Option<Permission>,
+) -> &'static str {
+    let owner = &repo["owner"];
+    if owner["login"].as_str().unwrap().eq_ignore_ascii_case(login) {
+        return "OWNER";
+    }
+    if owner["type"].as_str().eq(&Some("Organization")) {
+        let org = owner["login"].as_str().unwrap();
+        let url = format!("https://api.github.com/orgs/{org}/memberships/{login}");
+        if api::get_json(app, &url)
+            .await
+            .is_some_and(|m| m["state"].as_str().eq(&Some("active")))
+        {
+            return "MEMBER";
+        }
+    }
+    match permission {
+        Some(permission) if permission > Permission::Read => "COLLABORATOR",
+        _ => "NONE",
+    }
+}
+
+async fn team_member(app: &AppState, org: &str, slug: &str, login: &str) -> bool {
+    let url = format!("https://api.github.com/orgs/{org}/teams/{slug}/memberships/{login}");
+    match api::get_json(app, &url).await {
+        Some(json) => json["state"].as_str().eq(&Some("active")),
+        None => false,
+    }
+}
+
+pub async fn repo_permission(app: &AppState, repo_url: &str, login: &str) -> Option<Permission> {
+    let url = format!("{repo_url}/collaborators/{login}/permission");
+    let json = api::get_json(app, &url).await?;
+
+    // role_name knows about triage and maintain, custom roles fall back to permission
+    serde_json::from_value(json["role_name"].clone())
+        .or_else(|_| serde_json::from_value(json["permission"].clone()))
+        .ok()
+}
+
+/// Check a pull request has the approving reviews a workflow needs and no requested changes.
+/// Fails with a message saying what is missing.
+pub async fn reviews_satisfied(
+    app: &AppState,
+    rules: &WorkflowPolicy,
+    pull: &Value,
+) -> Result<(), String> {
+    if rules.approvals == 0 {
+        return Ok(());
+    }
+
+    let pull_url = pull["url"].as_str().unwrap();
+    let reviews = match api::get_all(app, &format!("{pull_url}/reviews")).await {
+        None => return Err("Could not get the reviews for this pull request.".to_string()),
+        Some(reviews) => reviews,
+    };
+    let (approvals, changes) = count_reviews(&reviews, pull["head"]["sha"].as_str().unwrap());
+
+    if approvals >= rules.approvals && changes == 0 {
+        return Ok(());
+    }
+    Err(format!(
+        "Needs {} approving review(s) of the latest commit and no requested changes, has {approvals} approval(s) and {changes} change request(s).",
+        rules.approvals
+    ))
+}
+
+/// Approvals of the head commit and requested changes, from reviewers who are part of the repository.
+/// Only a reviewer's latest approval, change request or dismissal counts.
+fn count_reviews(reviews: &[Value], head_sha: &str) -> (usize, usize) {
+    let mut latest = HashMap::new();
+    for review in reviews.iter() {
+        let association = review["author_association"].as_str().unwrap_or_default();
+        if !matches!(association, "OWNER" | "MEMBER" | "COLLABORATOR") {
+            continue;
+        }
+        let state = review["state"].as_str().unwrap_or_default();
+        if matches!(state, "APPROVED" | "CHANGES_REQUESTED" | "DISMISSED") {
+            let login = review["user"]["login"].as_str().unwrap_or_default();
+            latest.insert(login, review);
+        }
+    }
+
+    // Requested changes hold until they are dismissed, approvals are for the commit they were given on
+    let approvals = latest
+        .values()
+        .filter(|r| r["state"].as_str().eq(&Some("APPROVED")))
+        .filter(|r| r["commit_id"].as_str().eq(&Some(head_sha)))
+        .count();
+    let changes = latest
+        .values()
+        .filter(|r| r["state"].as_str().eq(&Some("CHANGES_REQUESTED")))
+        .count();
+    (approvals, changes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn source(user: &str, association: &str) -> Source {
+        let payload = json!({
+            "action": "created",
+            "repository": { "full_name": "owner/repo", "url": "https://api.github.com/repos/owner/repo", "owner": { "login": "owner" } },
+            "issue": {
+                "number": 1,
+                "comments_url": "https://api.github.com/repos/owner/repo/issues/1/comments",
+                "user": { "login": "author" },
+                "state": "open",
+            },
+            "comment": {
+                "id": 2,
+                "body": "!harmful ci",
+                "user": { "login": user },
+                "author_association": association,
+            },
+        });
+        Source::from_event("issue_comment", &payload).unwrap()
+    }
+
+    #[test]
+    fn denied_workflows_have_no_rules() {
+        let policy: Policy = serde_json::from_value(json!({
+            "workflows": { "release.yml": { "users": ["ada"] } },
+            "deny": ["secrets.yml"],
+        }))
+        .unwrap();
+        assert!(policy.for_workflow("secrets.yml").is_none());
+        assert_eq!(policy.for_workflow("release.yml").unwrap().users, ["ada"]);
+        assert_eq!(
+            policy.for_workflow("ci.yml").unwrap().associations,
+            WorkflowPolicy::maintainers().associations
+        );
+    }
+
+    #[test]
+    fn write_commands_fall_back_to_writers() {
+        let policy: Policy = serde_json::from_value(json!({
+            "commands": { "update": { "users": ["ada"] } },
+        }))
+        .unwrap();
+        assert_eq!(
+            policy.for_command("merge").permission,
+            Some(Permission::Write)
+        );
+        assert!(policy.for_command("merge").associations.is_empty());
+        assert_eq!(policy.for_command("update").users, ["ada"]);
+        assert_eq!(
+            policy.for_command("label").associations,
+            policy.default.associations
+        );
+    }
+
+    #[test]
+    fn repositories_layer_over_the_global_policy() {
+        let global: Policy = serde_json::from_value(json!({
+            "default": { "users": ["ada"], "deny": ["mallory"] },
+            "workflows": {
+                "release": { "teams": ["release"], "deny": ["eve"] },
+                "ci": { "users": ["bob"] },
+            },
+            "commands": { "merge": { "permission": "maintain" } },
+            "deny": ["secrets"],
+        }))
+        .unwrap();
+
+        // A partial table only replaces what it names
+        let repo: RepoPolicy = toml::from_str("[workflows.release]\nusers = [\"eve\"]\n").unwrap();
+        let policy = global.layer(repo);
+        assert_eq!(policy.default.users, ["ada"]);
+        assert_eq!(policy.workflows["ci"].users, ["bob"]);
+        assert_eq!(policy.workflows["release"].users, ["eve"]);
+        assert!(policy.workflows["release"].teams.is_empty());
+        assert_eq!(policy.workflows["release"].deny, ["eve"]);
+        assert_eq!(
+            policy.for_command("merge").permission,
+            Some(Permission::Maintain)
+        );
+        assert!(policy.for_workflow("secrets").is_none());
+
+        // Global denies are kept alongside the repository's own
+        let repo: RepoPolicy =
+            toml::from_str("deny = [\"deploy\"]\n[default]\nassociations = [\"NONE\"]\n").unwrap();
+        let policy = global.layer(repo);
+        assert!(policy.for_workflow("secrets").is_none());
+        assert!(policy.for_workflow("deploy").is_none());
+        assert_eq!(policy.default.associations, ["NONE"]);
+        assert_eq!(policy.default.deny, ["mallory"]);
+    }
+
+    fn review(login: &str, association: &str, state: &str, commit: &str) -> Value {
+        json!({
+            "user": { "login": login },
+            "author_association": association,
+            "state": state,
+            "commit_id": commit,
+        })
+    }
+
+    #[test]
+    fn latest_review_per_user_counts() {
+        let reviews = [
+            review("ada", "MEMBER", "CHANGES_REQUESTED", "old"),
+            review("ada", "MEMBER", "APPROVED", "head"),
+            review("ada", "MEMBER", "COMMENTED", "head"),
+            review("bob", "COLLABORATOR", "APPROVED", "head"),
+            review("bob", "COLLABORATOR", "DISMISSED", "head"),
+            review("eve", "OWNER", "CHANGES_REQUESTED", "old"),
+        ];
+        assert_eq!(count_reviews(&reviews, "head"), (1, 1));
+        assert_eq!(count_reviews(&reviews[..5], "head"), (1, 0));
+    }
+
+    #[test]
+    fn outside_and_stale_approvals_do_not_count() {
+        let reviews = [
+            review("drive-by", "NONE", "APPROVED", "head"),
+            review("contributor", "CONTRIBUTOR", "CHANGES_REQUESTED", "head"),
+            review("ada", "MEMBER", "APPROVED", "old"),
+        ];
+        assert_eq!(count_reviews(&reviews, "head"), (0, 0));
+    }
+
+    #[tokio::test]
+    async fn deny_beats_allow() {
+        let app = AppState::new_empty();
+        let rules = WorkflowPolicy {
+            users: vec!["ada".to_string()],
+            deny: vec!["Ada".to_string()],
+            ..WorkflowPolicy::maintainers()
+        };
+        assert!(!allowed_by(&app, &rules, &source("ada", "OWNER")).await);
+        assert!(allowed_by(&app, &rules, &source("bob", "MEMBER")).await);
+        assert!(
+            allowed_by(
+                &app,
+                &WorkflowPolicy::maintainers(),
+                &source("ADA", "OWNER")
+            )
+            .await
+        );
+    }
+
+    #[test]
+    fn permissions_are_ordered() {
+        assert!(Permission::None < Permission::Read);
+        assert!(Permission::Read < Permission::Triage);
+        assert!(Permission::Triage < Permission::Write);
+        assert!(Permission::Write < Permission::Maintain);
+        assert!(Permission::Maintain < Permission::Admin);
+        assert_eq!(
+            serde_json::from_value::<Permission>(json!("maintain")).unwrap(),
+            Permission::Maintain
+        );
+    }
+}
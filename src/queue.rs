@@ -0,0 +1,421 @@
+use crate::{
+    api, audit,
+    commands::Command,
+    config::{MergeMethod, RepoConfig},
+    merge::{self, Readiness, MERGE_POLICY},
+    policy,
+    sources::Source,
+    states::{AppState, ConnectionPool},
+    update,
+};
+use bb8_redis::redis::cmd;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+static QUEUE_EXPIRE: usize = 604800;
+/// Longest a webhook holds a queue while moving it, in case it dies on the way.
+static ADVANCE_EXPIRE: usize = 300;
+
+/// A pull request in a repository's merge queue.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Queued {
+    pub number: u64,
+    pub user: String,
+    pub method: MergeMethod,
+    /// Head commit it was queued at, or that the bot updated it to.
+    pub sha: String,
+    /// The bot asked GitHub to merge the base branch into it and is waiting for the new head.
+    pub updating: bool,
+    pub comments_url: String,
+}
+
+/// List of queued pull request numbers, first in line first.
+fn queue_key(repo: &str) -> String {
+    format!("queue.{repo}")
+}
+
+/// Hash of queued pull requests by number.
+fn entries_key(repo: &str) -> String {
+    format!("queued.{repo}")
+}
+
+fn advance_key(repo: &str) -> String {
+    format!("queue_advance.{repo}")
+}
+
+/// `queue [squash|rebase|merge]` adds the pull request to the merge queue, `queue --cancel` takes it out.
+pub async fn command(
+    app: &AppState,
+    pool: &ConnectionPool,
+    config: &RepoConfig,
+    source: &Source,
+    command: &Command<'_>,
+    entry: &mut audit::Entry,
+) {
+    let login = source.user.as_str();
+    let repo = source.repo_name();
+    let repo_url = source.repo["url"].as_str().unwrap();
+
+    if command.flag("cancel") {
+        let body = match remove(pool, repo, source.number).await {
+            false => "The pull request was not in the merge queue.".to_string(),
+            true => "The pull request is out of the merge queue.".to_string(),
+        };
+        api::comment(app, &source.reply_url, &body).await;
+        entry.allow("dequeued");
+        advance(app, pool, repo, repo_url).await;
+        return;
+    }
+
+    let method = match merge::method(config, command) {
+        None => {
+            let body = format!(
+                "Usage: `{} queue [squash|rebase|merge]`, or `{} queue --cancel`",
+                config.prefix, config.prefix
+            );
+            api::comment(app, &source.reply_url, &body).await;
+            entry.deny("unknown merge method");
+            return;
+        }
+        Some(method) => method,
+    };
+    entry.inputs = json!({ "method": method });
+
+    let pull_url = source.pull_url.as_deref().unwrap();
+    let (pull, readiness) = match merge::pull_readiness(app, repo_url, pull_url).await {
+        None => {
+            entry.allow("could not get pull request");
+            return;
+        }
+        Some(ready) => ready,
+    };
+
+    let rules = config.permissions.for_command(MERGE_POLICY);
+    if let Err(missing) = policy::reviews_satisfied(app, rules, &pull).await {
+        let body = format!("@{login} the pull request was not queued. {missing}");
+        api::comment(app, &source.reply_url, &body).await;
+        entry.deny(missing);
+        return;
+    }
+
+    // Being behind is what the queue is for, anything else that blocks a merge keeps it out
+    let behind = pull["mergeable_state"].as_str().eq(&Some("behind"));
+    if let Readiness::Blocked(reason) = readiness {
+        if !behind {
+            let body = format!("@{login} the pull request cannot be queued, {reason}.");
+            api::comment(app, &source.reply_url, &body).await;
+            entry.deny(reason);
+            return;
+        }
+    }
+
+    let queued = Queued {
+        number: source.number,
+        user: login.to_string(),
+        method,
+        sha: pull["head"]["sha"].as_str().unwrap().to_string(),
+        updating: false,
+        comments_url: source.comments_url.clone(),
+    };
+    let position = {
+        let mut conn = pool.as_ref().unwrap().get().await.unwrap();
+        let added: usize = cmd("HSETNX")
+            .arg(entries_key(repo))
+            .arg(source.number)
+            .arg(serde_json::to_string(&queued).unwrap())
+            .query_async(&mut *conn)
+            .await
+            .unwrap();
+        if added == 0 {
+            let body = "The pull request is already in the merge queue.";
+            api::comment(app, &source.reply_url, body).await;
+            entry.allow("already queued");
+            return;
+        }
+        let position: usize = cmd("RPUSH")
+            .arg(queue_key(repo))
+            .arg(source.number)
+            .query_async(&mut *conn)
+            .await
+            .unwrap();
+        for key in [queue_key(repo), entries_key(repo)] {
+            let _: usize = cmd("EXPIRE")
+                .arg(key)
+                .arg(QUEUE_EXPIRE)
+                .query_async(&mut *conn)
+                .await
+                .unwrap();
+        }
+        position
+    };
+    tracing::info!("Queued {repo} #{} at {position}", source.number);
+
+    let body = format!("The pull request is number {position} in the merge queue.");
+    api::comment(app, &source.reply_url, &body).await;
+    entry.allow(format!("queued at {position}"));
+
+    advance(app, pool, repo, repo_url).await;
+}
+
+/// Move the queue along when a `check_suite` or `check_run` completes in its repository.
+/// Check events do not name pull requests from forks, so any completed check looks at the front of the queue.
+pub async fn check_completed(app: &AppState, pool: &ConnectionPool, payload: &Value) {
+    if !payload["action"].as_str().unwrap().eq("completed") {
+        return;
+    }
+    let repo = &payload["repository"];
+    let full_name = repo["full_name"].as_str().unwrap();
+    if !list(pool, full_name).await.is_empty() {
+        advance(app, pool, full_name, repo["url"].as_str().unwrap()).await;
+    }
+}
+
+/// Take closed pull requests out of the queue and let the next one go.
+pub async fn pull_request(app: &AppState, pool: &ConnectionPool, payload: &Value) {
+    if !payload["action"].as_str().unwrap().eq("closed") {
+        return;
+    }
+    let repo = &payload["repository"];
+    let full_name = repo["full_name"].as_str().unwrap();
+    let number = payload["pull_request"]["number"].as_u64().unwrap();
+    if remove(pool, full_name, number).await {
+        advance(app, pool, full_name, repo["url"].as_str().unwrap()).await;
+    }
+}
+
+/// Queued pull requests of a repository, first in line first.
+pub async fn list(pool: &ConnectionPool, repo: &str) -> Vec<Queued> {
+    let mut conn = pool.as_ref().unwrap().get().await.unwrap();
+    let numbers: Vec<u64> = cmd("LRANGE")
+        .arg(queue_key(repo))
+        .arg(0)
+        .arg(-1)
+        .query_async(&mut *conn)
+        .await
+        .unwrap();
+    if numbers.is_empty() {
+        return Vec::new();
+    }
+    let entries: Vec<Option<String>> = cmd("HMGET")
+        .arg(entries_key(repo))
+        .arg(&numbers)
+        .query_async(&mut *conn)
+        .await
+        .unwrap();
+    entries
+        .iter()
+        .flatten()
+        .filter_map(|e| serde_json::from_str(e).ok())
+        .collect()
+}
+
+/// Take a pull request out of the queue, false if it was not in it.
+async fn remove(pool: &ConnectionPool, repo: &str, number: u64) -> bool {
+    let mut conn = pool.as_ref().unwrap().get().await.unwrap();
+    let removed: usize = cmd("HDEL")
+        .arg(entries_key(repo))
+        .arg(number)
+        .query_async(&mut *conn)
+        .await
+        .unwrap();
+    let _: usize = cmd("LREM")
+        .arg(queue_key(repo))
+        .arg(0)
+        .arg(number)
+        .query_async(&mut *conn)
+        .await
+        .unwrap();
+    removed > 0
+}
+
+async fn save(pool: &ConnectionPool, repo: &str, queued: &Queued) {
+    let mut conn = pool.as_ref().unwrap().get().await.unwrap();
+    let _: usize = cmd("HSET")
+        .arg(entries_key(repo))
+        .arg(queued.number)
+        .arg(serde_json::to_string(queued).unwrap())
+        .query_async(&mut *conn)
+        .await
+        .unwrap();
+}
+
+/// Take a pull request out of the queue and say why.
+async fn dequeue(app: &AppState, pool: &ConnectionPool, repo: &str, queued: &Queued, reason: &str) {
+    remove(pool, repo, queued.number).await;
+    tracing::info!("Dequeued {repo} #{}: {reason}", queued.number);
+
+    let body = format!(
+        "@{} the pull request was taken out of the merge queue, {reason}.",
+        queued.user
+    );
+    api::comment(app, &queued.comments_url, &body).await;
+}
+
+/// Work on the front of a repository's queue until it has to wait.
+/// Only one webhook moves a queue at a time, an event that finds it moving is left to the next one.
+async fn advance(app: &AppState, pool: &ConnectionPool, repo: &str, repo_url: &str) {
+    let key = advance_key(repo);
+    let taken: Option<String> = {
+        let mut conn = pool.as_ref().unwrap().get().await.unwrap();
+        cmd("SET")
+            .arg(&key)
+            .arg(1)
+            .arg("NX")
+            .arg("EX")
+            .arg(ADVANCE_EXPIRE)
+            .query_async(&mut *conn)
+            .await
+            .unwrap()
+    };
+    if taken.is_none() {
+        return;
+    }
+
+    while step(app, pool, repo, repo_url).await {}
+
+    let mut conn = pool.as_ref().unwrap().get().await.unwrap();
+    let _: usize = cmd("DEL").arg(&key).query_async(&mut *conn).await.unwrap();
+}
+
+/// Update, merge or drop the first pull request in the queue, true if it left the queue.
+async fn step(app: &AppState, pool: &ConnectionPool, repo: &str, repo_url: &str) -> bool {
+    let front: Option<(u64, Option<String>)> = {
+        let mut conn = pool.as_ref().unwrap().get().await.unwrap();
+        let number: Option<u64> = cmd("LINDEX")
+            .arg(queue_key(repo))
+            .arg(0)
+            .query_async(&mut *conn)
+            .await
+            .unwrap();
+        match number {
+            None => None,
+            Some(number) => {
+                let queued: Option<String> = cmd("HGET")
+                    .arg(entries_key(repo))
+                    .arg(number)
+                    .query_async(&mut *conn)
+                    .await
+                    .unwrap();
+                Some((number, queued))
+            }
+        }
+    };
+    let mut queued: Queued = match front {
+        None => return false,
+        Some((number, queued)) => match queued.and_then(|q| serde_json::from_str(&q).ok()) {
+            // Its entry expired
+            None => {
+                remove(pool, repo, number).await;
+                return true;
+            }
+            Some(queued) => queued,
+        },
+    };
+
+    let pull_url = format!("{repo_url}/pulls/{}", queued.number);
+    let (pull, readiness) = match merge::pull_readiness(app, repo_url, &pull_url).await {
+        None => return false,
+        Some(ready) => ready,
+    };
+    if pull["merged"].as_bool().unwrap_or(false) {
+        remove(pool, repo, queued.number).await;
+        return true;
+    }
+
+    // Only the merge of the base branch the bot asked for may move the head
+    let head_sha = pull["head"]["sha"].as_str().unwrap();
+    if !head_sha.eq(&queued.sha) {
+        if !queued.updating || !updated_from(app, repo_url, &pull, &queued.sha).await {
+            dequeue(app, pool, repo, &queued, "new commits were pushed").await;
+            return true;
+        }
+        queued.sha = head_sha.to_string();
+        queued.updating = false;
+        save(pool, repo, &queued).await;
+    }
+    else if queued.updating {
+        return false;
+    }
+
+    // Checks have to pass on what will be merged, so bring it up to date with the base branch first
+    let base_sha = match base_sha(app, repo_url, &pull).await {
+        None => return false,
+        Some(sha) => sha,
+    };
+    let compare = api::get_json(app, &format!("{repo_url}/compare/{base_sha}...{head_sha}")).await;
+    let behind = match compare {
+        None => return false,
+        Some(compare) => compare["behind_by"].as_u64().unwrap_or(0) > 0,
+    };
+    if behind {
+        return match update::update_branch(app, &pull_url, &pull).await {
+            Ok(()) => {
+                tracing::info!("Updating {repo} #{} for the merge queue", queued.number);
+                queued.updating = true;
+                save(pool, repo, &queued).await;
+                false
+            }
+            Err(reason) => {
+                dequeue(app, pool, repo, &queued, &reason).await;
+                true
+            }
+        };
+    }
+
+    match readiness {
+        Readiness::Waiting(_) => false,
+        Readiness::Blocked(reason) => {
+            dequeue(app, pool, repo, &queued, &reason).await;
+            true
+        }
+        Readiness::Ready if merge::merge(app, &pull_url, head_sha, queued.method).await => {
+            tracing::info!("Merged {repo} #{} from the merge queue", queued.number);
+            remove(pool, repo, queued.number).await;
+            true
+        }
+        Readiness::Ready => {
+            let reason = "GitHub would not merge the pull request";
+            dequeue(app, pool, repo, &queued, reason).await;
+            true
+        }
+    }
+}
+
+/// Head of the pull request's base branch.
+async fn base_sha(app: &AppState, repo_url: &str, pull: &Value) -> Option<String> {
+    let url = api::join_segment(
+        &format!("{repo_url}/branches"),
+        pull["base"]["ref"].as_str().unwrap(),
+    );
+    api::get_json(app, &url)
+        .await
+        .and_then(|b| b["commit"]["sha"].as_str().map(str::to_string))
+}
+
+/// Whether the pull request's head is a merge of the base branch into `sha`, like GitHub makes when updating.
+async fn updated_from(app: &AppState, repo_url: &str, pull: &Value, sha: &str) -> bool {
+    let head_sha = pull["head"]["sha"].as_str().unwrap();
+    let commit = match api::get_json(app, &format!("{repo_url}/commits/{head_sha}")).await {
+        None => return false,
+        Some(commit) => commit,
+    };
+    let parents: Vec<&str> = commit["parents"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|p| p["sha"].as_str())
+        .collect();
+    let merged = match parents[..] {
+        [first, merged] if first.eq(sha) => merged,
+        _ => return false,
+    };
+
+    // What was merged in has to be on the base branch already
+    let base_sha = match base_sha(app, repo_url, pull).await {
+        None => return false,
+        Some(sha) => sha,
+    };
+    api::get_json(app, &format!("{repo_url}/compare/{base_sha}...{merged}"))
+        .await
+        .is_some_and(|c| matches!(c["status"].as_str(), Some("identical" | "behind")))
+}
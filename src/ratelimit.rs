@@ -0,0 +1,59 @@
+use crate::{config::Limit, states::ConnectionPool};
+use bb8_redis::redis::cmd;
+use chrono::Utc;
+
+/// Token bucket in a hash, refilled by elapsed time on every take.
+/// Runs as one script so replicas sharing the store cannot race each other.
+static TAKE_SCRIPT: &str = r#"
+local capacity = tonumber(ARGV[1])
+local refill = tonumber(ARGV[2])
+local now = tonumber(ARGV[3])
+local bucket = redis.call('HMGET', KEYS[1], 'tokens', 'at')
+local tokens = tonumber(bucket[1]) or capacity
+local at = tonumber(bucket[2]) or now
+tokens = math.min(capacity, tokens + (now - at) / 1000 * refill)
+local wait = 0
+if tokens >= 1 then
+    tokens = tokens - 1
+else
+    wait = math.ceil((1 - tokens) / refill)
+end
+redis.call('HSET', KEYS[1], 'tokens', tostring(tokens), 'at', now)
+redis.call('EXPIRE', KEYS[1], math.ceil(capacity / refill) + 1)
+return wait
+"#;
+
+/// Take a token for a user running a workflow in a repository.
+/// Fails with the seconds to wait until the next token if the bucket is empty.
+pub async fn take(
+    pool: &ConnectionPool,
+    repo: &str,
+    user: &str,
+    workflow: &str,
+    limit: &Limit,
+) -> Result<(), u64> {
+    if limit.per_hour == 0 {
+        return Ok(());
+    }
+
+    let refill = f64::from(limit.per_hour) / 3600.0;
+    let mut conn = pool.as_ref().unwrap().get().await.unwrap();
+    let wait: u64 = cmd("EVAL")
+        .arg(TAKE_SCRIPT)
+        .arg(1)
+        .arg(format!("ratelimit.{repo}.{user}.{workflow}"))
+        .arg(limit.burst.max(1))
+        .arg(refill)
+        .arg(Utc::now().timestamp_millis())
+        .query_async(&mut *conn)
+        .await
+        .unwrap();
+
+    match wait {
+        0 => Ok(()),
+        wait => {
+            tracing::info!("Rate limited {user} running {workflow} in {repo} for {wait}s");
+            Err(wait)
+        }
+    }
+}
@@ -0,0 +1,270 @@
+use crate::{
+    api,
+    config::{self, ReleaseNotes},
+    states::{AppState, ConnectionPool},
+};
+use reqwest::Url;
+use serde_json::{json, Value};
+use std::collections::HashSet;
+
+/// Redraft the release notes when the default branch is pushed to.
+pub async fn pushed(app: &AppState, pool: &ConnectionPool, payload: &Value) {
+    let repo = &payload["repository"];
+    let default_ref = format!("refs/heads/{}", repo["default_branch"].as_str().unwrap());
+    if !payload["ref"].as_str().unwrap().eq(&default_ref)
+        || payload["deleted"].as_bool().unwrap_or(false)
+    {
+        return;
+    }
+    draft(app, pool, repo).await;
+}
+
+/// Redraft the release notes when a release is created, its tag starts the next notes.
+pub async fn release_created(app: &AppState, pool: &ConnectionPool, payload: &Value) {
+    // Drafts are created by us, or are someone's own notes
+    if !payload["action"].as_str().unwrap().eq("created")
+        || payload["release"]["draft"].as_bool().unwrap_or(false)
+    {
+        return;
+    }
+    draft(app, pool, &payload["repository"]).await;
+}
+
+/// Create or update the draft release with the pull requests merged since the last release.
+async fn draft(app: &AppState, pool: &ConnectionPool, repo: &Value) {
+    let config = match config::load(app, pool, repo).await {
+        None => return,
+        Some(config) => config,
+    };
+    if !config.features.release_notes {
+        return;
+    }
+    let settings = &config.release_notes;
+
+    let repo_url = repo["url"].as_str().unwrap();
+    let branch = repo["default_branch"].as_str().unwrap();
+    let latest = match api::get_optional(app, &format!("{repo_url}/releases/latest")).await {
+        Err(()) => return,
+        Ok(latest) => latest,
+    };
+    let latest_tag = latest.as_ref().and_then(|l| l["tag_name"].as_str());
+    let pulls = match latest_tag {
+        Some(tag) => {
+            let url = format!("{repo_url}/compare/{tag}...{branch}");
+            let commits = match compare_commits(app, &url).await {
+                None => return,
+                Some(commits) => commits,
+            };
+
+            let mut seen = HashSet::new();
+            let mut pulls = Vec::new();
+            for commit in &commits {
+                let sha = commit["sha"].as_str().unwrap();
+                let associated = api::get_json(app, &format!("{repo_url}/commits/{sha}/pulls"))
+                    .await
+                    .unwrap_or(Value::Null);
+                for pull in associated.as_array().into_iter().flatten() {
+                    let merged = !pull["merged_at"].is_null()
+                        && pull["base"]["ref"].as_str().eq(&Some(branch));
+                    if merged && seen.insert(pull["number"].as_u64().unwrap()) {
+                        pulls.push(pull.clone());
+                    }
+                }
+            }
+            pulls
+        }
+        // Nothing released yet, so every pull request merged into the branch
+        None => {
+            let url = Url::parse_with_params(
+                &format!("{repo_url}/pulls"),
+                [("state", "closed"), ("base", branch)],
+            )
+            .unwrap();
+            match api::get_all(app, url.as_str()).await {
+                None => return,
+                Some(pulls) => pulls
+                    .into_iter()
+                    .filter(|p| !p["merged_at"].is_null())
+                    .collect(),
+            }
+        }
+    };
+
+    let body = render(settings, &pulls);
+    let releases = match api::get_all(app, &format!("{repo_url}/releases")).await {
+        None => return,
+        Some(releases) => releases,
+    };
+    let existing = releases.iter().find(|r| {
+        r["draft"].as_bool().unwrap_or(false)
+            && r["tag_name"]
+                .as_str()
+                .eq(&Some(settings.draft_tag.as_str()))
+    });
+    let full_name = repo["full_name"].as_str().unwrap();
+    match existing {
+        Some(release) => {
+            let url = release["url"].as_str().unwrap();
+            let json = json!({ "body": body });
+            if api::patch_json(app, url, &json).await.is_some() {
+                tracing::info!("Updated draft release notes for {full_name}");
+            }
+        }
+        // A draft published without renaming its tag holds the tag, a new draft could not have it
+        None if latest_tag.eq(&Some(settings.draft_tag.as_str())) => {
+            tracing::warn!(
+                "Not drafting release notes for {full_name}, the latest release is tagged `{}`",
+                settings.draft_tag
+            );
+        }
+        None => {
+            let json = json!({
+                "tag_name": settings.draft_tag,
+                "target_commitish": branch,
+                "name": settings.draft_name,
+                "body": body,
+                "draft": true,
+            });
+            if api::post_json(app, &format!("{repo_url}/releases"), &json)
+                .await
+                .is_some()
+            {
+                tracing::info!("Created draft release notes for {full_name}");
+            }
+        }
+    }
+}
+
+/// Every commit of a compare, which pages its commits next to `total_commits`.
+async fn compare_commits(app: &AppState, url: &str) -> Option<Vec<Value>> {
+    let mut commits = Vec::new();
+    for page in 1.. {
+        let json = api::get_json(app, &format!("{url}?per_page=100&page={page}")).await?;
+        let list = json["commits"].as_array()?;
+        commits.extend(list.iter().cloned());
+        let total = json["total_commits"].as_u64().unwrap_or(0) as usize;
+        if list.len() < 100 || commits.len() >= total {
+            break;
+        }
+    }
+    Some(commits)
+}
+
+/// Group pull requests into the configured categories, in order, the first matching category wins.
+fn render(settings: &ReleaseNotes, pulls: &[Value]) -> String {
+    let labels = |pull: &Value| -> Vec<String> {
+        pull["labels"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|l| l["name"].as_str().map(str::to_string))
+            .collect()
+    };
+    let change = |pull: &Value| {
+        settings
+            .change_template
+            .replace("$TITLE", pull["title"].as_str().unwrap_or_default())
+            .replace("$NUMBER", &pull["number"].to_string())
+            .replace(
+                "$AUTHOR",
+                pull["user"]["login"].as_str().unwrap_or_default(),
+            )
+    };
+
+    let mut sections: Vec<(&str, Vec<String>)> = settings
+        .categories
+        .iter()
+        .map(|c| (c.title.as_str(), Vec::new()))
+        .collect();
+    let mut other = Vec::new();
+    for pull in pulls {
+        let labels = labels(pull);
+        if labels.iter().any(|l| settings.exclude_labels.contains(l)) {
+            continue;
+        }
+        match settings
+            .categories
+            .iter()
+            .position(|c| c.labels.iter().any(|l| labels.contains(l)))
+        {
+            Some(i) => sections[i].1.push(change(pull)),
+            None => other.push(change(pull)),
+        }
+    }
+    sections.push((settings.other.as_str(), other));
+
+    let changes = sections
+        .iter()
+        .filter(|(title, changes)| !title.is_empty() && !changes.is_empty())
+        .map(|(title, changes)| format!("### {title}\n\n{}", changes.join("\n")))
+        .collect::<Vec<_>>()
+        .join("\n\n");
+    if changes.is_empty() {
+        settings.template.replace("$CHANGES", &settings.no_changes)
+    }
+    else {
+        settings.template.replace("$CHANGES", &changes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Category;
+
+    fn pull(number: u64, labels: &[&str]) -> Value {
+        json!({
+            "number": number,
+            "title": format!("Change {number}"),
+            "user": { "login": "ada" },
+            "labels": labels.iter().map(|l| json!({ "name": l })).collect::<Vec<_>>(),
+        })
+    }
+
+    fn settings() -> ReleaseNotes {
+        ReleaseNotes {
+            categories: vec![
+                Category {
+                    title: "Features".to_string(),
+                    labels: vec!["feature".to_string()],
+                },
+                Category {
+                    title: "Fixes".to_string(),
+                    labels: vec!["bug".to_string()],
+                },
+            ],
+            exclude_labels: vec!["skip".to_string()],
+            ..ReleaseNotes::default()
+        }
+    }
+
+    #[test]
+    fn groups_by_first_category() {
+        let pulls = [
+            pull(1, &["bug"]),
+            pull(2, &["bug", "feature"]),
+            pull(3, &[]),
+            pull(4, &["feature", "skip"]),
+        ];
+        assert_eq!(
+            render(&settings(), &pulls),
+            "## What's changed\n\n\
+            ### Features\n\n- Change 2 (#2) @ada\n\n\
+            ### Fixes\n\n- Change 1 (#1) @ada\n\n\
+            ### Changes\n\n- Change 3 (#3) @ada"
+        );
+    }
+
+    #[test]
+    fn leaves_out_uncategorized_without_other() {
+        let settings = ReleaseNotes {
+            other: String::new(),
+            ..settings()
+        };
+        assert_eq!(
+            render(&settings, &[pull(3, &[])]),
+            "## What's changed\n\nNo changes."
+        );
+        assert_eq!(render(&settings, &[]), "## What's changed\n\nNo changes.");
+    }
+}
@@ -0,0 +1,395 @@
+use crate::{
+    api,
+    config::{self, RepoConfig},
+    states::{AppState, ConnectionPool},
+    status,
+};
+use bb8_redis::redis::cmd;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+/// User id of the bot, whose workflow runs get check runs.
+static BOT_ID: u64 = 130938523;
+static TRACK_EXPIRE: usize = 21600;
+/// How long a dispatch blocks duplicates before its run is found, runs without the id in their name never are.
+static CLAIM_EXPIRE: usize = 600;
+
+/// Prefix of correlation ids, workflows put the id in their `run-name` so it can be found.
+pub static ID_PREFIX: &str = "harmful-";
+
+/// A dispatch from a comment, waiting for its workflow run.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Tracked {
+    /// Correlation id.
+    pub id: String,
+    /// Repository full name.
+    pub repo: String,
+    /// Pull request number.
+    pub number: u64,
+    pub workflow: String,
+    pub comment_id: u64,
+    pub comments_url: String,
+    /// None for reviews, which cannot be reacted to.
+    pub reactions_url: Option<String>,
+    pub run_id: Option<u64>,
+    /// Commit the run is for, its own head is the default branch.
+    #[serde(default)]
+    pub sha: Option<String>,
+}
+
+/// A dispatch that has not finished, stored per pull request and workflow.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Active {
+    /// Correlation id.
+    pub id: String,
+    pub html_url: Option<String>,
+}
+
+fn active_key(tracked: &Tracked) -> String {
+    format!(
+        "active.{}.{}.{}",
+        tracked.repo, tracked.number, tracked.workflow
+    )
+}
+
+/// Mark a dispatch as active for its pull request and workflow, for a short while until its run is found.
+/// Unless forced, fails with the active dispatch if there already is one.
+pub async fn claim(pool: &ConnectionPool, tracked: &Tracked, force: bool) -> Result<(), Active> {
+    let key = active_key(tracked);
+    let active = Active {
+        id: tracked.id.clone(),
+        html_url: None,
+    };
+
+    let mut conn = pool.as_ref().unwrap().get().await.unwrap();
+    let mut set = cmd("SET");
+    set.arg(&key)
+        .arg(serde_json::to_string(&active).unwrap())
+        .arg("EX")
+        .arg(CLAIM_EXPIRE);
+    if !force {
+        set.arg("NX");
+    }
+    let reply: Option<String> = set.query_async(&mut *conn).await.unwrap();
+    if reply.is_some() {
+        return Ok(());
+    }
+
+    let existing: Option<String> = cmd("GET").arg(&key).query_async(&mut *conn).await.unwrap();
+    match existing {
+        Some(existing) => Err(serde_json::from_str(&existing).unwrap()),
+        // Finished in between
+        None => Ok(()),
+    }
+}
+
+/// Clear the active dispatch for a pull request and workflow, if it is still this one.
+pub async fn release(pool: &ConnectionPool, tracked: &Tracked) {
+    let key = active_key(tracked);
+    let mut conn = pool.as_ref().unwrap().get().await.unwrap();
+    let existing: Option<String> = cmd("GET").arg(&key).query_async(&mut *conn).await.unwrap();
+    let ours = existing
+        .and_then(|e| serde_json::from_str::<Active>(&e).ok())
+        .map(|e| e.id.eq(&tracked.id))
+        .unwrap_or(false);
+    if ours {
+        let _: usize = cmd("DEL").arg(&key).query_async(&mut *conn).await.unwrap();
+    }
+}
+
+/// New correlation id for a dispatch from a comment.
+pub fn new_id(comment_id: u64) -> String {
+    format!(
+        "{ID_PREFIX}{comment_id}-{:x}",
+        Utc::now().timestamp_millis()
+    )
+}
+
+/// Remember a dispatch until its workflow run is requested.
+pub async fn track(pool: &ConnectionPool, id: &str, tracked: &Tracked) {
+    let mut conn = pool.as_ref().unwrap().get().await.unwrap();
+    let reply: String = cmd("SET")
+        .arg(format!("dispatch.{id}"))
+        .arg(serde_json::to_string(tracked).unwrap())
+        .arg("EX")
+        .arg(TRACK_EXPIRE)
+        .query_async(&mut *conn)
+        .await
+        .unwrap();
+    tracing::debug!("Tracking dispatch {id}: {reply}");
+}
+
+pub async fn workflow_run(app: &AppState, pool: &ConnectionPool, payload: &Value) {
+    // Runs the bot started are still followed up when the config cannot be read
+    let config = config::load(app, pool, &payload["repository"])
+        .await
+        .unwrap_or_default();
+    let workflow_run = &payload["workflow_run"];
+    let by_bot = workflow_run["actor"]["id"].as_u64().eq(&Some(BOT_ID));
+
+    match payload["action"].as_str().unwrap() {
+        "requested" => {
+            let tracked = correlate(pool, workflow_run).await;
+            if let Some(tracked) = &tracked {
+                if config.features.status_comment {
+                    status::update(app, pool, tracked, workflow_run).await;
+                }
+            }
+            if by_bot && config.features.check_runs {
+                let sha = tracked.as_ref().and_then(|t| t.sha.as_deref());
+                check_run_requested(app, pool, workflow_run, sha).await;
+            }
+        }
+        "in_progress" => {
+            if config.features.status_comment {
+                let run_id = workflow_run["id"].as_u64().unwrap();
+                if let Some(tracked) = tracked_run(pool, run_id).await {
+                    status::update(app, pool, &tracked, workflow_run).await;
+                }
+            }
+            if by_bot && config.features.check_runs {
+                check_run_in_progress(app, pool, workflow_run).await;
+            }
+        }
+        "completed" => {
+            report(app, pool, &config, workflow_run).await;
+            if by_bot && config.features.check_runs {
+                check_run_completed(app, pool, workflow_run).await;
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Find the correlation id in a requested run's title and store the run against the comment.
+async fn correlate(pool: &ConnectionPool, workflow_run: &Value) -> Option<Tracked> {
+    let title = workflow_run["display_title"].as_str().unwrap_or_default();
+    let id = match title
+        .split_whitespace()
+        .map(|word| word.trim_matches(|c: char| !c.is_ascii_alphanumeric() && c != '-'))
+        .find(|word| word.starts_with(ID_PREFIX))
+    {
+        None => return None,
+        Some(id) => id,
+    };
+
+    let tracked: Option<String> = {
+        let mut conn = pool.as_ref().unwrap().get().await.unwrap();
+        cmd("GETDEL")
+            .arg(format!("dispatch.{id}"))
+            .query_async(&mut *conn)
+            .await
+            .unwrap()
+    };
+    let mut tracked: Tracked = serde_json::from_str(&tracked?).unwrap();
+
+    let run_id = workflow_run["id"].as_u64().unwrap();
+    tracked.run_id = Some(run_id);
+    set_tracked_run(pool, run_id, &tracked).await;
+
+    // Link the active dispatch to its run, if it was not overridden, and hold it while the run goes
+    let key = active_key(&tracked);
+    let mut conn = pool.as_ref().unwrap().get().await.unwrap();
+    let existing: Option<String> = cmd("GET").arg(&key).query_async(&mut *conn).await.unwrap();
+    let existing = existing.and_then(|e| serde_json::from_str::<Active>(&e).ok());
+    if let Some(mut active) = existing.filter(|e| e.id.eq(id)) {
+        active.html_url = workflow_run["html_url"].as_str().map(str::to_string);
+        let _: Option<String> = cmd("SET")
+            .arg(&key)
+            .arg(serde_json::to_string(&active).unwrap())
+            .arg("EX")
+            .arg(TRACK_EXPIRE)
+            .arg("XX")
+            .query_async(&mut *conn)
+            .await
+            .unwrap();
+    }
+
+    tracing::info!(
+        "Dispatch {id} from comment {} is run {run_id}",
+        tracked.comment_id
+    );
+    Some(tracked)
+}
+
+/// React to the triggering comment once a tracked run finishes,
+/// and either update the status comment or reply with a link.
+async fn report(app: &AppState, pool: &ConnectionPool, config: &RepoConfig, workflow_run: &Value) {
+    let run_id = workflow_run["id"].as_u64().unwrap();
+    let tracked = match tracked_run(pool, run_id).await {
+        None => return,
+        Some(tracked) => tracked,
+    };
+
+    let conclusion = workflow_run["conclusion"].as_str().unwrap_or("unknown");
+    let html_url = workflow_run["html_url"].as_str().unwrap();
+    let reaction = match conclusion {
+        "success" => "hooray",
+        "cancelled" | "skipped" | "neutral" => "confused",
+        _ => "-1",
+    };
+    if let Some(reactions_url) = &tracked.reactions_url {
+        api::react(app, reactions_url, reaction).await;
+    }
+
+    if config.features.status_comment {
+        status::update(app, pool, &tracked, workflow_run).await;
+    }
+    else {
+        let body = format!(
+            "Workflow `{}` finished: **{conclusion}**\n{html_url}",
+            tracked.workflow
+        );
+        api::comment(app, &tracked.comments_url, &body).await;
+    }
+
+    release(pool, &tracked).await;
+
+    let mut conn = pool.as_ref().unwrap().get().await.unwrap();
+    let _: usize = cmd("DEL")
+        .arg(format!("run.{run_id}"))
+        .query_async(&mut *conn)
+        .await
+        .unwrap();
+}
+
+async fn tracked_run(pool: &ConnectionPool, run_id: u64) -> Option<Tracked> {
+    let mut conn = pool.as_ref().unwrap().get().await.unwrap();
+    let tracked: Option<String> = cmd("GET")
+        .arg(format!("run.{run_id}"))
+        .query_async(&mut *conn)
+        .await
+        .unwrap();
+    tracked.map(|t| serde_json::from_str(&t).unwrap())
+}
+
+async fn set_tracked_run(pool: &ConnectionPool, run_id: u64, tracked: &Tracked) {
+    let mut conn = pool.as_ref().unwrap().get().await.unwrap();
+    let _: String = cmd("SET")
+        .arg(format!("run.{run_id}"))
+        .arg(serde_json::to_string(tracked).unwrap())
+        .arg("EX")
+        .arg(TRACK_EXPIRE)
+        .query_async(&mut *conn)
+        .await
+        .unwrap();
+}
+
+/// Start a check run for a workflow run, on the pull request's commit when the run was tracked.
+async fn check_run_requested(
+    app: &AppState,
+    pool: &ConnectionPool,
+    workflow_run: &Value,
+    sha: Option<&str>,
+) {
+    let id = workflow_run["id"].as_u64().unwrap();
+    let name = workflow_run["display_title"].as_str().unwrap();
+    let head_sha = sha.unwrap_or(workflow_run["head_sha"].as_str().unwrap());
+    let details_url = workflow_run["html_url"].as_str().unwrap();
+    let started_at = workflow_run["run_started_at"].as_str().unwrap();
+    let status = workflow_run["status"].as_str().unwrap();
+
+    let url = workflow_run["head_repository"]["url"].as_str().unwrap();
+    let url = format!("{url}/check-runs");
+
+    let mut json_map = Map::new();
+    json_map.insert("name".to_string(), Value::String(name.to_string()));
+    json_map.insert("head_sha".to_string(), Value::String(head_sha.to_string()));
+    json_map.insert(
+        "details_url".to_string(),
+        Value::String(details_url.to_string()),
+    );
+    json_map.insert(
+        "started_at".to_string(),
+        Value::String(started_at.to_string()),
+    );
+    json_map.insert("status".to_string(), Value::String(status.to_string()));
+    let json = Value::Object(json_map);
+
+    if let Some(cr_json) = api::post_json(app, &url, &json).await {
+        let cr_id = cr_json["id"].as_u64().unwrap();
+        tracing::info!("Created check run {cr_id} for workflow {id}");
+
+        let mut conn = pool.as_ref().unwrap().get().await.unwrap();
+        let reply: String = cmd("SET")
+            .arg(format!("workflow_cr.{id}"))
+            .arg(cr_id)
+            .arg("EX")
+            .arg(TRACK_EXPIRE)
+            .query_async(&mut *conn)
+            .await
+            .unwrap();
+        tracing::debug!("Set workflow cr {id} to {cr_id}: {reply}");
+    }
+}
+
+async fn check_run_in_progress(app: &AppState, pool: &ConnectionPool, workflow_run: &Value) {
+    let id = workflow_run["id"].as_u64().unwrap();
+    let status = workflow_run["status"].as_str().unwrap();
+
+    let cr_id = match check_run_id(pool, id).await {
+        None => return,
+        Some(cr) => cr,
+    };
+
+    let url = workflow_run["head_repository"]["url"].as_str().unwrap();
+    let url = format!("{url}/check-runs/{cr_id}");
+
+    let mut json_map = Map::new();
+    json_map.insert("status".to_string(), Value::String(status.to_string()));
+    let json = Value::Object(json_map);
+
+    if api::patch_json(app, &url, &json).await.is_some() {
+        tracing::info!("Updated check run {cr_id} to in_progress for workflow {id}");
+    }
+}
+
+async fn check_run_completed(app: &AppState, pool: &ConnectionPool, workflow_run: &Value) {
+    let id = workflow_run["id"].as_u64().unwrap();
+    let status = workflow_run["status"].as_str().unwrap();
+    let conclusion = workflow_run["conclusion"].as_str().unwrap();
+    let completed_at = workflow_run["updated_at"].as_str().unwrap();
+
+    let cr_id = match check_run_id(pool, id).await {
+        None => return,
+        Some(cr) => cr,
+    };
+
+    let url = workflow_run["head_repository"]["url"].as_str().unwrap();
+    let url = format!("{url}/check-runs/{cr_id}");
+
+    let mut json_map = Map::new();
+    json_map.insert("status".to_string(), Value::String(status.to_string()));
+    json_map.insert(
+        "conclusion".to_string(),
+        Value::String(conclusion.to_string()),
+    );
+    json_map.insert(
+        "completed_at".to_string(),
+        Value::String(completed_at.to_string()),
+    );
+    let json = Value::Object(json_map);
+
+    if api::patch_json(app, &url, &json).await.is_some() {
+        tracing::info!("Updated check run {cr_id} to completed for workflow {id}");
+
+        let mut conn = pool.as_ref().unwrap().get().await.unwrap();
+        let _: usize = cmd("DEL")
+            .arg(format!("workflow_cr.{id}"))
+            .query_async(&mut *conn)
+            .await
+            .unwrap();
+    }
+}
+
+async fn check_run_id(pool: &ConnectionPool, id: u64) -> Option<u64> {
+    let mut conn = pool.as_ref().unwrap().get().await.unwrap();
+    let reply: Option<u64> = cmd("GET")
+        .arg(format!("workflow_cr.{id}"))
+        .query_async(&mut *conn)
+        .await
+        .unwrap();
+    tracing::debug!("Get workflow cr {id}: {reply:?}");
+    reply
+}
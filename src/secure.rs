@@ -115,6 +115,42 @@ impl AccessToken {
             .send()
     }
 
+    pub async fn put_json<U: IntoUrl>(
+        &mut self,
+        app_data: &AppData,
+        url: U,
+        payload: &Value,
+    ) -> impl Future<Output = Result<Response, reqwest::Error>> {
+        let token = self
+            .get_installation_token(app_data)
+            .await
+            .expect("Could not get token");
+        fetch::CLIENT
+            .put(url)
+            .bearer_auth(token)
+            .header("Accept", "application/vnd.github+json")
+            .header("X-GitHub-Api-Version", "2022-11-28")
+            .json(payload)
+            .send()
+    }
+
+    pub async fn delete<U: IntoUrl>(
+        &mut self,
+        app_data: &AppData,
+        url: U,
+    ) -> impl Future<Output = Result<Response, reqwest::Error>> {
+        let token = self
+            .get_installation_token(app_data)
+            .await
+            .expect("Could not get token");
+        fetch::CLIENT
+            .delete(url)
+            .bearer_auth(token)
+            .header("Accept", "application/vnd.github+json")
+            .header("X-GitHub-Api-Version", "2022-11-28")
+            .send()
+    }
+
     async fn get_installation_token(&mut self, app_data: &AppData) -> Result<String, ()> {
         if Utc::now() < self.expiry {
             return Ok(self.token.clone());
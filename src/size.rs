@@ -0,0 +1,113 @@
+use crate::{
+    api, autolabel, checks, config,
+    states::{AppState, ConnectionPool},
+};
+use serde_json::{json, Value};
+
+static SIZES: [&str; 5] = ["XS", "S", "M", "L", "XL"];
+static CHECK_NAME: &str = "size";
+
+/// Label a pull request by the lines it changes and fail the size check when it is too big.
+pub async fn pull_request(app: &AppState, pool: &ConnectionPool, payload: &Value) {
+    let action = payload["action"].as_str().unwrap();
+    if !matches!(
+        action,
+        "opened" | "synchronize" | "reopened" | "labeled" | "unlabeled"
+    ) {
+        return;
+    }
+
+    let config = match config::load(app, pool, &payload["repository"]).await {
+        None => return,
+        Some(config) => config,
+    };
+    let settings = &config.size;
+    if !config.features.size_check {
+        return;
+    }
+    // Label changes only matter for the override, size labels are our own
+    if action.ends_with("labeled")
+        && !payload["label"]["name"]
+            .as_str()
+            .eq(&Some(&settings.override_label))
+    {
+        return;
+    }
+
+    let pull = &payload["pull_request"];
+    let pull_url = pull["url"].as_str().unwrap();
+    let mut lines = pull["additions"].as_u64().unwrap() + pull["deletions"].as_u64().unwrap();
+    let mut excluded = 0;
+    if !settings.exclude.is_empty() {
+        let generated = autolabel::glob_set(&settings.exclude);
+        let files = match api::get_all(app, &format!("{pull_url}/files")).await {
+            None => return,
+            Some(files) => files,
+        };
+        for file in files
+            .iter()
+            .filter(|f| generated.is_match(f["filename"].as_str().unwrap()))
+        {
+            excluded += file["changes"].as_u64().unwrap_or(0);
+        }
+        lines = lines.saturating_sub(excluded);
+    }
+
+    let size = settings
+        .limits
+        .iter()
+        .take(SIZES.len() - 1)
+        .position(|limit| lines < *limit)
+        .map_or(SIZES[SIZES.len() - 1], |i| SIZES[i]);
+    let label = format!("{}{size}", settings.label_prefix);
+
+    let issue_url = pull["issue_url"].as_str().unwrap();
+    for other in SIZES.map(|s| format!("{}{s}", settings.label_prefix)) {
+        if !other.eq(&label) && checks::has_label(pull, &other) {
+            let url = api::join_segment(&format!("{issue_url}/labels"), &other);
+            api::delete(app, &url).await;
+        }
+    }
+    if !checks::has_label(pull, &label) {
+        let json = json!({ "labels": [label] });
+        api::post_json(app, &format!("{issue_url}/labels"), &json).await;
+    }
+
+    let overridden = checks::has_label(pull, &settings.override_label);
+    let too_big = settings.fail_above.is_some_and(|max| lines > max);
+    let mut summary = format!("{lines} lines changed");
+    if excluded > 0 {
+        summary.push_str(&format!(", not counting {excluded} in generated files"));
+    }
+    summary.push('.');
+    if let (true, Some(max)) = (too_big, settings.fail_above) {
+        summary.push_str(&format!(
+            "\n\nPull requests over {max} lines are hard to review, please split it up."
+        ));
+        if overridden {
+            summary.push_str(&format!(
+                " Passing anyway, it has the `{}` label.",
+                settings.override_label
+            ));
+        }
+        else {
+            summary.push_str(&format!(
+                " A maintainer can add the `{}` label to let it through.",
+                settings.override_label
+            ));
+        }
+    }
+
+    let output = json!({ "title": format!("Size {size}"), "summary": summary });
+    let repo_url = payload["repository"]["url"].as_str().unwrap();
+    let head_sha = pull["head"]["sha"].as_str().unwrap();
+    checks::complete(
+        app,
+        repo_url,
+        head_sha,
+        CHECK_NAME,
+        !too_big || overridden,
+        output,
+    )
+    .await;
+}
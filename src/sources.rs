@@ -0,0 +1,202 @@
+use crate::policy::Permission;
+use serde_json::Value;
+
+/// Where a command was written, each kind replies and reacts in its own way.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SourceKind {
+    /// A comment on an issue or pull request's conversation.
+    IssueComment,
+    /// A comment on a line of a pull request's diff.
+    ReviewComment,
+    /// The body of a submitted review.
+    Review,
+    /// A label added to a pull request, there is no body.
+    Label,
+}
+
+/// A comment or review that may hold a command, with what is needed to answer it.
+#[derive(Debug, Clone)]
+pub struct Source {
+    pub kind: SourceKind,
+    pub body: String,
+    /// Body before an edit, None if the source is new.
+    pub previous_body: Option<String>,
+    pub user: String,
+    pub association: String,
+    /// The user's permission on the repository, when it is already known.
+    pub permission: Option<Permission>,
+    /// Repository object from the webhook payload.
+    pub repo: Value,
+    /// Issue or pull request number.
+    pub number: u64,
+    /// Who opened the issue or pull request.
+    pub author: String,
+    /// Api url of the pull request, None on plain issues.
+    pub pull_url: Option<String>,
+    pub open: bool,
+    pub id: u64,
+    /// Comments url of the issue or pull request conversation.
+    pub comments_url: String,
+    /// Where replies go, a thread reply for review comments.
+    pub reply_url: String,
+    /// Reviews cannot be reacted to.
+    pub reactions_url: Option<String>,
+}
+impl Source {
+    /// Read a source from a comment or review webhook, None for events and actions without one
+    /// and for edits by someone other than the author.
+    pub fn from_event(event: &str, payload: &Value) -> Option<Self> {
+        let action = payload["action"].as_str().unwrap();
+        let previous_body = match action {
+            "edited" => Some(payload["changes"]["body"]["from"].as_str()?.to_string()),
+            _ => None,
+        };
+
+        let repo = payload["repository"].clone();
+        let source = match (event, action) {
+            ("issue_comment", "created" | "edited") => {
+                let issue = &payload["issue"];
+                let comment = &payload["comment"];
+                let comments_url = issue["comments_url"].as_str().unwrap().to_string();
+                Some(Self {
+                    kind: SourceKind::IssueComment,
+                    body: comment["body"].as_str().unwrap_or_default().to_string(),
+                    previous_body,
+                    user: comment["user"]["login"].as_str().unwrap().to_string(),
+                    association: comment["author_association"].as_str().unwrap().to_string(),
+                    permission: None,
+                    repo,
+                    number: issue["number"].as_u64().unwrap(),
+                    author: issue["user"]["login"].as_str().unwrap().to_string(),
+                    pull_url: issue["pull_request"]["url"].as_str().map(str::to_string),
+                    open: issue["state"].as_str().unwrap().eq("open"),
+                    id: comment["id"].as_u64().unwrap(),
+                    reply_url: comments_url.clone(),
+                    comments_url,
+                    reactions_url: comment["reactions"]["url"].as_str().map(str::to_string),
+                })
+            }
+            ("pull_request_review_comment", "created" | "edited") => {
+                let pull = &payload["pull_request"];
+                let comment = &payload["comment"];
+                let pull_url = pull["url"].as_str().unwrap().to_string();
+                let id = comment["id"].as_u64().unwrap();
+                Some(Self {
+                    kind: SourceKind::ReviewComment,
+                    body: comment["body"].as_str().unwrap_or_default().to_string(),
+                    previous_body,
+                    user: comment["user"]["login"].as_str().unwrap().to_string(),
+                    association: comment["author_association"].as_str().unwrap().to_string(),
+                    permission: None,
+                    repo,
+                    number: pull["number"].as_u64().unwrap(),
+                    author: pull["user"]["login"].as_str().unwrap().to_string(),
+                    open: pull["state"].as_str().unwrap().eq("open"),
+                    id,
+                    comments_url: pull["comments_url"].as_str().unwrap().to_string(),
+                    reply_url: format!("{pull_url}/comments/{id}/replies"),
+                    pull_url: Some(pull_url),
+                    reactions_url: comment["reactions"]["url"].as_str().map(str::to_string),
+                })
+            }
+            ("pull_request_review", "submitted" | "edited") => {
+                let pull = &payload["pull_request"];
+                let review = &payload["review"];
+                let comments_url = pull["comments_url"].as_str().unwrap().to_string();
+                Some(Self {
+                    kind: SourceKind::Review,
+                    // Reviews can be submitted without a body
+                    body: review["body"].as_str().unwrap_or_default().to_string(),
+                    previous_body,
+                    user: review["user"]["login"].as_str().unwrap().to_string(),
+                    association: review["author_association"].as_str().unwrap().to_string(),
+                    permission: None,
+                    repo,
+                    number: pull["number"].as_u64().unwrap(),
+                    author: pull["user"]["login"].as_str().unwrap().to_string(),
+                    pull_url: Some(pull["url"].as_str().unwrap().to_string()),
+                    open: pull["state"].as_str().unwrap().eq("open"),
+                    id: review["id"].as_u64().unwrap(),
+                    reply_url: comments_url.clone(),
+                    comments_url,
+                    reactions_url: None,
+                })
+            }
+            _ => None,
+        }?;
+
+        // Anyone with write access can edit someone else's comment, only the author's own edits count
+        let sender = payload["sender"]["login"].as_str().unwrap_or_default();
+        if source.previous_body.is_some() && !sender.eq_ignore_ascii_case(&source.user) {
+            tracing::info!(
+                "Ignoring an edit by {sender} of a comment by {}",
+                source.user
+            );
+            return None;
+        }
+        Some(source)
+    }
+
+    /// Source for a `pull_request.labeled` webhook.
+    /// The sender has no association in the payload, so it has to be looked up along with their permission.
+    pub fn from_label(payload: &Value, association: &str, permission: Option<Permission>) -> Self {
+        let pull = &payload["pull_request"];
+        let comments_url = pull["comments_url"].as_str().unwrap().to_string();
+        Self {
+            kind: SourceKind::Label,
+            body: String::new(),
+            previous_body: None,
+            user: payload["sender"]["login"].as_str().unwrap().to_string(),
+            association: association.to_string(),
+            permission,
+            repo: payload["repository"].clone(),
+            number: pull["number"].as_u64().unwrap(),
+            author: pull["user"]["login"].as_str().unwrap().to_string(),
+            pull_url: Some(pull["url"].as_str().unwrap().to_string()),
+            open: pull["state"].as_str().unwrap().eq("open"),
+            id: payload["label"]["id"].as_u64().unwrap(),
+            reply_url: comments_url.clone(),
+            comments_url,
+            reactions_url: None,
+        }
+    }
+
+    pub fn repo_name(&self) -> &str {
+        self.repo["full_name"].as_str().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn edited(sender: &str) -> Value {
+        json!({
+            "action": "edited",
+            "sender": { "login": sender },
+            "changes": { "body": { "from": "Looks good" } },
+            "repository": { "full_name": "owner/repo" },
+            "issue": {
+                "number": 1,
+                "comments_url": "https://api.github.com/repos/owner/repo/issues/1/comments",
+                "user": { "login": "author" },
+                "state": "open",
+            },
+            "comment": {
+                "id": 2,
+                "body": "Looks good\n!harmful deploy production",
+                "user": { "login": "owner" },
+                "author_association": "OWNER",
+            },
+        })
+    }
+
+    #[test]
+    fn edits_only_count_from_the_author() {
+        let source = Source::from_event("issue_comment", &edited("Owner")).unwrap();
+        assert_eq!(source.user, "owner");
+        assert_eq!(source.previous_body.as_deref(), Some("Looks good"));
+        assert!(Source::from_event("issue_comment", &edited("writer")).is_none());
+    }
+}
@@ -0,0 +1,165 @@
+use crate::{
+    api,
+    runs::Tracked,
+    states::{AppState, ConnectionPool},
+};
+use bb8_redis::redis::cmd;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+
+static STATUS_EXPIRE: usize = 604800;
+static STATUS_MARKER: &str = "<!-- harmful-status -->";
+
+/// One workflow run in a pull request's status comment.
+#[derive(Debug, Serialize, Deserialize)]
+struct Section {
+    at: i64,
+    body: String,
+}
+
+/// Update a run's section in the pull request's status comment, posting the comment if there is none.
+///
+/// Store layout is a hash per pull request at `status.{repo}.{number}`,
+/// with the comment id at `comment` and a section per correlation id.
+pub async fn update(
+    app: &AppState,
+    pool: &ConnectionPool,
+    tracked: &Tracked,
+    workflow_run: &Value,
+) {
+    let key = format!("status.{}.{}", tracked.repo, tracked.number);
+    let section = Section {
+        at: Utc::now().timestamp_millis(),
+        body: render_run(app, tracked, workflow_run).await,
+    };
+
+    let fields: HashMap<String, String> = {
+        let mut conn = pool.as_ref().unwrap().get().await.unwrap();
+        // Keep where the run was first listed, so sections do not move around
+        let at: Option<String> = cmd("HGET")
+            .arg(&key)
+            .arg(&tracked.id)
+            .query_async(&mut *conn)
+            .await
+            .unwrap();
+        let at = at
+            .and_then(|s| serde_json::from_str::<Section>(&s).ok())
+            .map(|s| s.at)
+            .unwrap_or(section.at);
+        let section = Section { at, ..section };
+
+        let _: usize = cmd("HSET")
+            .arg(&key)
+            .arg(&tracked.id)
+            .arg(serde_json::to_string(&section).unwrap())
+            .query_async(&mut *conn)
+            .await
+            .unwrap();
+        let _: usize = cmd("EXPIRE")
+            .arg(&key)
+            .arg(STATUS_EXPIRE)
+            .query_async(&mut *conn)
+            .await
+            .unwrap();
+        cmd("HGETALL")
+            .arg(&key)
+            .query_async(&mut *conn)
+            .await
+            .unwrap()
+    };
+
+    let mut sections: Vec<Section> = fields
+        .iter()
+        .filter(|(field, _)| !field.as_str().eq("comment"))
+        .filter_map(|(_, value)| serde_json::from_str(value).ok())
+        .collect();
+    sections.sort_by_key(|s| s.at);
+    let body = sections
+        .into_iter()
+        .map(|s| s.body)
+        .collect::<Vec<_>>()
+        .join("\n\n");
+    let json = json!({ "body": format!("{STATUS_MARKER}\n### Workflow status\n\n{body}") });
+
+    // Edit the comment in place, or post a new one if there is none or it was deleted
+    if let Some(comment_id) = fields.get("comment") {
+        let url = format!(
+            "https://api.github.com/repos/{}/issues/comments/{comment_id}",
+            tracked.repo
+        );
+        if api::patch_json(app, &url, &json).await.is_some() {
+            tracing::debug!("Updated status comment {comment_id} on {key}");
+            return;
+        }
+    }
+
+    if let Some(comment) = api::post_json(app, &tracked.comments_url, &json).await {
+        let comment_id = comment["id"].as_u64().unwrap();
+        let mut conn = pool.as_ref().unwrap().get().await.unwrap();
+        let _: usize = cmd("HSET")
+            .arg(&key)
+            .arg("comment")
+            .arg(comment_id)
+            .query_async(&mut *conn)
+            .await
+            .unwrap();
+        tracing::info!("Posted status comment {comment_id} on {key}");
+    }
+}
+
+async fn render_run(app: &AppState, tracked: &Tracked, workflow_run: &Value) -> String {
+    let status = workflow_run["status"].as_str().unwrap_or("queued");
+    let state = match (status, workflow_run["conclusion"].as_str()) {
+        ("completed", Some(conclusion)) => conclusion,
+        _ => status,
+    };
+    let html_url = workflow_run["html_url"].as_str().unwrap();
+    let run_number = &workflow_run["run_number"];
+    let mut body = format!(
+        "**`{}`** {} **{}** ([run #{run_number}]({html_url}))",
+        tracked.workflow,
+        symbol(state),
+        state.replace('_', " ")
+    );
+
+    if status.eq("queued") || status.eq("requested") {
+        return body;
+    }
+
+    let jobs_url = workflow_run["jobs_url"].as_str().unwrap();
+    let jobs = match api::get_json(app, jobs_url).await {
+        None => return body,
+        Some(jobs) => jobs,
+    };
+    let jobs = jobs["jobs"].as_array().cloned().unwrap_or_default();
+    if !jobs.is_empty() {
+        body.push_str("\n\n| Job | Result |\n| --- | --- |");
+        for job in jobs.iter() {
+            let state = job["conclusion"]
+                .as_str()
+                .or(job["status"].as_str())
+                .unwrap_or("queued");
+            body.push_str(&format!(
+                "\n| [{}]({}) | {} {} |",
+                job["name"].as_str().unwrap_or("job"),
+                job["html_url"].as_str().unwrap_or(html_url),
+                symbol(state),
+                state.replace('_', " ")
+            ));
+        }
+    }
+    body
+}
+
+/// Symbol for a run, job or deployment state.
+pub fn symbol(state: &str) -> &'static str {
+    match state {
+        "success" => "✅",
+        "failure" | "error" | "timed_out" | "startup_failure" => "❌",
+        "cancelled" | "skipped" | "neutral" | "stale" | "inactive" => "⚪",
+        "action_required" => "⚠️",
+        _ => "⏳",
+    }
+}
@@ -0,0 +1,195 @@
+use crate::{api, audit, commands::Command, config::RepoConfig, sources::Source, states::AppState};
+use serde_json::json;
+
+/// `label`, `unlabel`, `assign` and `request-review`, done with the app's token within the configured allowlists.
+/// The author of an issue or pull request may always triage it, others need the command's entry of `permissions.commands`.
+pub async fn command(
+    app: &AppState,
+    config: &RepoConfig,
+    source: &Source,
+    command: &Command<'_>,
+    entry: &mut audit::Entry,
+) {
+    let login = source.user.as_str();
+
+    let pull_url = source.pull_url.as_deref();
+    if command.name.eq("request-review") && pull_url.is_none() {
+        let body = format!("@{login} reviews can only be requested on pull requests.");
+        api::comment(app, &source.reply_url, &body).await;
+        entry.deny("not a pull request");
+        return;
+    }
+
+    let mut names = parse_names(command);
+    let mut allowlist = match command.name {
+        "label" | "unlabel" => config.triage.labels.clone(),
+        "assign" => config.triage.assignees.clone(),
+        _ => config.triage.reviewers.clone(),
+    };
+    // Anyone who may triage can take it on themselves
+    if command.name.eq("assign") {
+        if names.is_empty() {
+            names.push(login.to_string());
+        }
+        allowlist.push(login.to_string());
+    }
+    entry.inputs = json!({ "names": names });
+
+    let (allowed, refused) = split_allowed(&names, &allowlist);
+    if allowed.is_empty() {
+        let body = format!(
+            "@{login} {}",
+            refused_reply(config, command, &refused, &allowlist)
+        );
+        api::comment(app, &source.reply_url, &body).await;
+        entry.deny(format!("nothing allowed to {}", command.name));
+        return;
+    }
+
+    let repo_url = source.repo["url"].as_str().unwrap();
+    let issue_url = format!("{repo_url}/issues/{}", source.number);
+    let done = match command.name {
+        "label" => {
+            let json = json!({ "labels": allowed });
+            api::post_json(app, &format!("{issue_url}/labels"), &json)
+                .await
+                .is_some()
+        }
+        "unlabel" => {
+            let mut done = true;
+            for label in &allowed {
+                let url = api::join_segment(&format!("{issue_url}/labels"), label);
+                done &= api::delete(app, &url).await.is_some();
+            }
+            done
+        }
+        "assign" => {
+            let json = json!({ "assignees": allowed });
+            api::post_json(app, &format!("{issue_url}/assignees"), &json)
+                .await
+                .is_some()
+        }
+        _ => {
+            // Teams are given as `org/team`, the api wants their slug
+            let (teams, users): (Vec<&String>, Vec<&String>) =
+                allowed.iter().partition(|r| r.contains('/'));
+            let teams: Vec<&str> = teams
+                .iter()
+                .filter_map(|t| t.split_once('/').map(|(_, slug)| slug))
+                .collect();
+            let json = json!({ "reviewers": users, "team_reviewers": teams });
+            let url = format!("{}/requested_reviewers", pull_url.unwrap());
+            api::post_json(app, &url, &json).await.is_some()
+        }
+    };
+
+    if !done {
+        entry.allow(format!("{} failed", command.name));
+        return;
+    }
+    if let Some(reactions_url) = &source.reactions_url {
+        api::react(app, reactions_url, "+1").await;
+    }
+    if !refused.is_empty() {
+        let body = format!(
+            "@{login} {}",
+            refused_reply(config, command, &refused, &allowlist)
+        );
+        api::comment(app, &source.reply_url, &body).await;
+    }
+    entry.allow(format!("{} done", command.name));
+}
+
+/// Labels are separated by commas since they can hold spaces, users and teams by spaces or commas.
+fn parse_names(command: &Command<'_>) -> Vec<String> {
+    let args = command.args.unwrap_or_default();
+    let names: Vec<&str> = match command.name {
+        "label" | "unlabel" => args.split(',').collect(),
+        _ => args
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .collect(),
+    };
+    names
+        .iter()
+        .map(|n| n.trim().trim_start_matches('@'))
+        .filter(|n| !n.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Split names into the ones on an allowlist, with their allowlist spelling, and the refused ones.
+fn split_allowed(names: &[String], allowlist: &[String]) -> (Vec<String>, Vec<String>) {
+    let mut allowed = Vec::new();
+    let mut refused = Vec::new();
+    for name in names {
+        match allowlist.iter().find(|a| a.eq_ignore_ascii_case(name)) {
+            Some(a) => allowed.push(a.clone()),
+            None => refused.push(name.clone()),
+        }
+    }
+    (allowed, refused)
+}
+
+fn refused_reply(
+    config: &RepoConfig,
+    command: &Command<'_>,
+    refused: &[String],
+    allowlist: &[String],
+) -> String {
+    let quote = |names: &[String]| {
+        names
+            .iter()
+            .map(|n| format!("`{n}`"))
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    let mut reply = if refused.is_empty() {
+        format!("Usage: `{} {} <names>`.", config.prefix, command.name)
+    }
+    else {
+        format!("{} cannot be used with `{}`.", quote(refused), command.name)
+    };
+    if !allowlist.is_empty() {
+        reply.push_str(&format!(" Allowed are {}.", quote(allowlist)));
+    }
+    reply
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(name: &str, args: &str) -> Vec<String> {
+        parse_names(&Command {
+            name,
+            flags: Vec::new(),
+            args: Some(args),
+        })
+    }
+
+    #[test]
+    fn labels_split_on_commas_only() {
+        assert_eq!(
+            names("label", "good first issue, bug,,"),
+            ["good first issue", "bug"]
+        );
+    }
+
+    #[test]
+    fn users_split_on_commas_and_spaces() {
+        assert_eq!(
+            names("request-review", "@alice, @org/team  bob"),
+            ["alice", "org/team", "bob"]
+        );
+    }
+
+    #[test]
+    fn allowlist_spelling_wins() {
+        let (allowed, refused) = split_allowed(
+            &["BUG".to_string(), "wontfix".to_string()],
+            &["bug".to_string()],
+        );
+        assert_eq!(allowed, ["bug"]);
+        assert_eq!(refused, ["wontfix"]);
+    }
+}
@@ -0,0 +1,168 @@
+use crate::{
+    api, audit, backport,
+    commands::Command,
+    config,
+    sources::Source,
+    states::{AppState, ConnectionPool},
+};
+use reqwest::Url;
+use serde_json::{json, Value};
+
+/// `update` merges the base branch into the pull request, `rebase` replays its commits on top of the base branch.
+pub async fn command(
+    app: &AppState,
+    source: &Source,
+    command: &Command<'_>,
+    pull: &Value,
+    entry: &mut audit::Entry,
+) {
+    let login = source.user.as_str();
+    let pull_url = source.pull_url.as_deref().unwrap();
+
+    let repo_url = source.repo["url"].as_str().unwrap();
+    let updated = match command.name {
+        "rebase" => rebase(app, repo_url, pull_url, pull).await,
+        _ => update_branch(app, pull_url, pull).await,
+    };
+    match updated {
+        Ok(()) => {
+            if let Some(reactions_url) = &source.reactions_url {
+                api::react(app, reactions_url, "+1").await;
+            }
+            entry.allow("updated");
+        }
+        Err(reason) => {
+            let body = format!("@{login} the branch was not updated, {reason}.");
+            api::comment(app, &source.reply_url, &body).await;
+            entry.allow(reason);
+        }
+    }
+}
+
+/// Update open pull requests with the auto update label when their base branch is pushed to.
+pub async fn base_pushed(app: &AppState, pool: &ConnectionPool, payload: &Value) {
+    let branch = match payload["ref"].as_str().unwrap().strip_prefix("refs/heads/") {
+        Some(branch) if !payload["deleted"].as_bool().unwrap_or(false) => branch,
+        _ => return,
+    };
+
+    let repo = &payload["repository"];
+    let config = match config::load(app, pool, repo).await {
+        None => return,
+        Some(config) => config,
+    };
+    let label = match &config.update.label {
+        None => return,
+        Some(label) => label,
+    };
+
+    let repo_url = repo["url"].as_str().unwrap();
+    let url = Url::parse_with_params(
+        &format!("{repo_url}/pulls"),
+        [("state", "open"), ("base", branch)],
+    )
+    .unwrap();
+    let pulls = match api::get_all(app, url.as_str()).await {
+        None => return,
+        Some(pulls) => pulls,
+    };
+
+    let after = payload["after"].as_str().unwrap();
+    for pull in pulls.iter().filter(|p| {
+        p["labels"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .any(|l| l["name"].as_str().eq(&Some(label)))
+    }) {
+        // Pull requests that already have the pushed commits are left alone
+        let head_sha = pull["head"]["sha"].as_str().unwrap();
+        let compare = api::get_json(app, &format!("{repo_url}/compare/{after}...{head_sha}")).await;
+        if compare.is_none_or(|c| c["behind_by"].as_u64().unwrap_or(0) == 0) {
+            continue;
+        }
+
+        let pull_url = pull["url"].as_str().unwrap();
+        match update_branch(app, pull_url, pull).await {
+            Ok(()) => tracing::info!("Auto updated {pull_url}"),
+            Err(reason) => tracing::debug!("Could not auto update {pull_url}: {reason}"),
+        }
+    }
+}
+
+/// Merge the base branch into the pull request, the same as the button on GitHub.
+pub async fn update_branch(app: &AppState, pull_url: &str, pull: &Value) -> Result<(), String> {
+    let json = json!({ "expected_head_sha": pull["head"]["sha"] });
+    api::put_json(app, &format!("{pull_url}/update-branch"), &json)
+        .await
+        .map(|_| ())
+        .ok_or_else(|| "GitHub could not merge the base branch into it".to_string())
+}
+
+/// Cherry-pick the pull request's commits onto its base branch and move its head there.
+/// Merge commits are dropped, like `git rebase` does.
+async fn rebase(
+    app: &AppState,
+    repo_url: &str,
+    pull_url: &str,
+    pull: &Value,
+) -> Result<(), String> {
+    let head = &pull["head"];
+    if !head["repo"]["url"].as_str().eq(&Some(repo_url)) {
+        return Err("branches in forks cannot be rebased by the bot".to_string());
+    }
+
+    let commits: Vec<Value> = api::get_all(app, &format!("{pull_url}/commits"))
+        .await
+        .ok_or_else(|| "could not get its commits".to_string())?
+        .iter()
+        .filter(|c| c["parents"].as_array().is_some_and(|p| p.len() == 1))
+        .map(|c| {
+            json!({
+                "sha": c["sha"],
+                "parents": c["parents"],
+                "message": c["commit"]["message"],
+                "author": c["commit"]["author"],
+            })
+        })
+        .collect();
+
+    let number = pull["number"].as_u64().unwrap();
+    let base_sha = api::get_json(
+        app,
+        &api::join_segment(
+            &format!("{repo_url}/branches"),
+            pull["base"]["ref"].as_str().unwrap(),
+        ),
+    )
+    .await
+    .and_then(|b| b["commit"]["sha"].as_str().map(str::to_string))
+    .ok_or_else(|| "could not get the base branch".to_string())?;
+
+    // Work on a scratch branch, so the pull request only moves once everything applied
+    let scratch = format!("harmful-rebase-{number}");
+    let json = json!({ "ref": format!("refs/heads/{scratch}"), "sha": base_sha });
+    api::post_json(app, &format!("{repo_url}/git/refs"), &json)
+        .await
+        .ok_or_else(|| format!("could not create `{scratch}`, a rebase may be running"))?;
+    let picked = backport::cherry_pick(app, repo_url, &scratch, &base_sha, &commits).await;
+    api::delete(app, &format!("{repo_url}/git/refs/heads/{scratch}")).await;
+    let new_head = picked?;
+
+    // Commits pushed while picking would be lost, the api has no compare and swap so check right before
+    let branch = head["ref"].as_str().unwrap();
+    let current = api::get_json(app, &format!("{repo_url}/git/ref/heads/{branch}"))
+        .await
+        .and_then(|r| r["object"]["sha"].as_str().map(str::to_string))
+        .ok_or_else(|| format!("could not get `{branch}`"))?;
+    if !head["sha"].as_str().eq(&Some(current.as_str())) {
+        return Err(format!(
+            "`{branch}` was pushed to during the rebase, try again"
+        ));
+    }
+    let json = json!({ "sha": new_head, "force": true });
+    api::patch_json(app, &format!("{repo_url}/git/refs/heads/{branch}"), &json)
+        .await
+        .map(|_| ())
+        .ok_or_else(|| format!("could not push to `{branch}`"))
+}